@@ -0,0 +1,7 @@
+//Roland Ballinger - roland2@pdx.edu
+//M68K Processor Emulator
+//CS 410P - Rust Programming
+
+//The emulator core lives in a library so that hosts and device models can
+//drive it directly, main.rs is just a front end that runs a binary file.
+pub mod m68k;
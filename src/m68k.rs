@@ -17,19 +17,36 @@
 //  mnemonics they replace, and can be found  under those names.     //
 ///////////////////////////////////////////////////////////////////////
 
-use std::env;
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
-use std::io::BufReader;
 use std::io::Read;
 
+//What happened on a call to run.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    Running, //executed an instruction or took an interrupt
+    Stopped, //waiting in a STOP for an interrupt
+    Halted, //ran off the end of the program, or double faulted
+}
+
 pub struct M68k {
-    a: [u32; 8],
+    a: [u32; 8], //a[7] is the stack pointer for whichever mode we're in
     d: [u32; 8],
     pc: u32, //program counter
     sr: u16, //status register - bits are:
-    //0: carry, 1: overflow, 2: zero, 3: negative, 4: extend, 5-14: ???, 15: trace enabled
+    //0: carry, 1: overflow, 2: zero, 3: negative, 4: extend, 8-10: interrupt mask,
+    //13: supervisor mode, 15: trace enabled
+    usp: u32, //user stack pointer, while in supervisor mode
+    ssp: u32, //supervisor stack pointer, while in user mode
+    ipl: u8, //interrupt level devices are requesting, 0 for none
+    int_vector: Option<u8>, //vector supplied on acknowledge, None to autovector
+    nmi: bool, //level 7 is edge triggered, this is set when it's first asserted
+    stopped: bool, //waiting for an interrupt after a STOP
+    tracing: bool, //the T bit was set when the current instruction started
+    fault: Option<Fault>, //why the current instruction is being abandoned
+    halted: bool, //double faulted, only a hard reset gets out of this
+    raw: bool, //running a raw binary, which halts at the end of the file
+    reset_line: Option<Box<dyn FnMut()>>, //devices reset by the RESET instruction
     op: u16,
     prog: Vec<u8>,
     memory: Mem,
@@ -38,51 +55,196 @@ pub struct M68k {
 impl M68k {
     pub fn init() -> M68k {
         M68k {
-            a: [0 as u32; 8],
-            d: [0 as u32; 8],
-            pc: 0 as u32,
-            sr: 0 as u16,
-            op: 0 as u16,
+            a: [0; 8],
+            d: [0; 8],
+            pc: 0,
+            sr: SUPERVISOR, //the 68000 always starts out in supervisor mode
+            usp: 0,
+            ssp: 0,
+            ipl: 0,
+            int_vector: None,
+            nmi: false,
+            stopped: false,
+            tracing: false,
+            fault: None,
+            halted: false,
+            raw: false,
+            reset_line: None,
+            op: 0,
             prog: Vec::new(),
             memory: Mem::new(),
         }
     }
 
-    pub fn load(&mut self, mut file: File) -> Result<(), ()> {
-        file.read_to_end(&mut self.prog);
-        self.a[7] = 0xffffff;
+    //The program is copied into memory at address 0, so that it can be
+    //fetched from and addressed relative to the PC like any other data.
+    //By default it's treated as a raw binary and run as code from address
+    //0, with the stack at the top of memory, until it runs off the end. For
+    //an image that starts with a vector table, like a ROM, call hard_reset
+    //afterwards to boot it the way the real CPU would.
+    pub fn load(&mut self, mut file: File) -> io::Result<()> {
+        file.read_to_end(&mut self.prog)?;
+        for (addr, byte) in self.prog.iter().enumerate() {
+            self.memory.mem_write(addr, *byte as u32, 1);
+        }
+        self.a[7] = ADDR_MASK as u32 + 1;
         self.pc = 0;
+        self.raw = true;
         Ok(())
     }
 
+    //The power-on reset sequence: supervisor mode with tracing off and all
+    //interrupts masked, then the initial SSP from vector 0 and the initial
+    //PC from vector 1. Anything that was pending, including a level 7 edge,
+    //is forgotten.
+    pub fn hard_reset(&mut self) {
+        self.set_sr(SUPERVISOR | 0x700);
+        self.a[7] = self.read_mem(0, 4);
+        self.pc = self.read_mem(4, 4);
+        self.ipl = 0;
+        self.int_vector = None;
+        self.nmi = false;
+        self.tracing = false;
+        self.stopped = false;
+        self.halted = false;
+        self.fault = None;
+        self.raw = false;
+    }
+
+    //Registers something to be called whenever the RESET instruction pulses
+    //the reset line, so devices can put themselves back in their reset state.
+    pub fn on_reset(&mut self, devices: Box<dyn FnMut()>) {
+        self.reset_line = Some(devices);
+    }
+
     fn next_op(&mut self) -> u16 {
-        let temp: u16 =
-            ((self.prog[self.pc as usize] as u16) << 8) + 
-                self.prog[self.pc as usize + 1] as u16;
-        self.pc += 2;
+        let pc = self.pc;
+        if !self.aligned(pc, 2, true, true) {
+            self.pc = pc.wrapping_add(2);
+            return 0;
+        }
+        let temp = self.memory.read_w(pc as usize);
+        self.pc = self.pc.wrapping_add(2);
         temp
     }
 
+    //Lets a device or the host drive the IPL pins. level is 1-7, or 0 when
+    //nothing is requesting an interrupt, and it's up to the device to drop
+    //it again once it has been serviced. When the CPU acknowledges the
+    //interrupt the device can supply its own vector number, or None to have
+    //the CPU use the autovector for that level. A device that can't say who
+    //interrupted should supply 24, the spurious interrupt vector.
+    pub fn set_ipl(&mut self, level: u8, vector: Option<u8>) {
+        let level = level & 0b111;
+        if level == 7 && self.ipl != 7 {
+            self.nmi = true;
+        }
+        self.ipl = level;
+        self.int_vector = vector;
+    }
+
+    //Checked before each instruction. Interrupts above the mask in the SR
+    //are taken, and the mask is raised to their level so they can only be
+    //interrupted by something higher. Level 7 can't be masked, so to stop
+    //it being taken over and over it only counts when it's first asserted.
+    fn interrupt(&mut self) -> bool {
+        let level = self.ipl;
+        let mask = ((self.sr >> 8) & 0b111) as u8;
+        if level == 7 {
+            if !self.nmi {
+                return false;
+            }
+            self.nmi = false;
+        }
+        else if level <= mask {
+            return false;
+        }
+        let vector = match self.int_vector {
+            Some(vector) => vector as u32,
+            None => AUTOVECTOR_BASE + level as u32,
+        };
+        self.exception(vector);
+        self.sr = (self.sr & !0x700) | ((level as u16) << 8);
+        true
+    }
+
+    //Runs one step: takes a pending interrupt if there is one, otherwise
+    //executes the next instruction. While the CPU is sitting in a STOP this
+    //does nothing but wait for an interrupt, and says so.
+    pub fn run(&mut self) -> Status {
+        if self.halted {
+            return Status::Halted;
+        }
+        if self.interrupt() {
+            self.stopped = false;
+        }
+        else {
+            if self.stopped {
+                return Status::Stopped;
+            }
+            if self.raw && self.pc as usize >= self.prog.len() {
+                return Status::Halted; //ran off the end of the program
+            }
+            //the T bit is checked before the instruction, so the one that
+            //turns tracing on isn't traced, and the one that turns it off is
+            self.tracing = self.sr & TRACE != 0;
+            let saved = (self.pc, self.d, self.a, self.sr, self.usp, self.ssp);
+            self.execute();
+            if self.fault.is_some() {
+                //the instruction is abandoned, so put back everything it
+                //changed before it got that far. The exception then stacks
+                //the address of the instruction, and the SR it started with.
+                let (pc, d, a, sr, usp, ssp) = saved;
+                self.pc = pc;
+                self.d = d;
+                self.a = a;
+                self.sr = sr;
+                self.usp = usp;
+                self.ssp = ssp;
+            }
+            else if self.tracing {
+                //this comes after any trap the instruction took, so the
+                //handler for that is where the trace returns to. It also
+                //ends a STOP.
+                self.stopped = false;
+                self.exception(TRACE_EXCEPTION);
+            }
+            self.tracing = false;
+        }
+        match self.fault.take() {
+            Some(Fault::Address(addr, status)) => self.address_error(addr, status),
+            Some(Fault::Illegal) => self.exception(ILLEGAL_INSTRUCTION),
+            None => {}
+        }
+        if self.halted {
+            return Status::Halted;
+        }
+        if self.stopped {
+            return Status::Stopped;
+        }
+        Status::Running
+    }
+
     //This method is extremely important, and represents the core functional
     //loop. Each loop, the variable op gets the next opcode from the program
     //file. The first 4 digits of op are read to determine the type of op being
     //performed. The encoding of the rest of the op is dependent on the op
     //itself, so each opcode has its own parsing rules. The corresponding
     //match blocks are commented with the mnemonic being decoded.
-    pub fn run(&mut self) -> bool {
-        println!("{}", self.pc);
-        debug_print(&self);
+    fn execute(&mut self) {
         self.op = self.next_op();
+        if self.fault.is_some() {
+            return; //the opcode itself was fetched from an odd address
+        }
         match self.op {
-            0 => {return true;}
-            0b0100101011111100 => {self.illegal(); return false;},
-            0b0100111001110000 => {self.reset(); return true;},
-            0b0100111001110001 => {return true;}//this is a nop
-            0b0100111001110010 => {self.stop(); return false;},
-            0b0100111001110011 => {self.rte(); return true;},
-            0b0100111001110101 => {self.rts(); return true;},
-            0b0100111001110110 => {self.trapv(); return false;},
-            0b0100111001110111 => {self.rtr(); return true;},
+            0b0100101011111100 => {self.illegal(); return;},
+            0b0100111001110000 => {self.reset(); return;},
+            0b0100111001110001 => {return;}//this is a nop
+            0b0100111001110010 => {self.stop(); return;},
+            0b0100111001110011 => {self.rte(); return;},
+            0b0100111001110101 => {self.rts(); return;},
+            0b0100111001110110 => {self.trapv(); return;},
+            0b0100111001110111 => {self.rtr(); return;},
             _ => {}
         }
         match (self.op >> 12) & 0xf {
@@ -96,19 +258,21 @@ impl M68k {
                     0b0110 => self.addi(),
                     0b1010 => self.eori(),
                     0b1100 => self.cmpi(),
-                    0b1000 => {
-                        match (self.op >> 6) & 0b111 {//dest for these is the Z bit of the SR
+                    //with bit 8 set the bit ops take the bit number from a d
+                    //register, and with an address register it's a movep
+                    op if op & 1 != 0 && (self.op >> 3) & 0b111 == 0b001 => self.movep(),
+                    op if op == 0b1000 || op & 1 != 0 => {
+                        match (self.op >> 6) & 0b11 {//dest for these is the Z bit of the SR
                             0 => self.btst(),
                             1 => self.bchg(),
                             2 => self.bclr(),
-                            3 => self.bset(),
-                            _ => {}
+                            _ => self.bset(),
                         }
                     }
-                    _ => self.movep(), //this is the only other immediate op
+                    _ => self.illegal(),
                 }
             }
-            0b0001 | 0b0010 | 0b0011 => self.mov(),
+            0b0001..=0b0011 => self.mov(),
             0b0100 => {
                 //this block contains LOTS of misc operations
                 match self.op{
@@ -118,11 +282,23 @@ impl M68k {
                     op if (op & 0b111111000000) == 0b101011000000 => self.tas(),
                     op if (op & 0b111111000000) == 0b111010000000 => self.jsr(),
                     op if (op & 0b111111000000) == 0b111011000000 => self.jmp(),
+                    op if (op & 0b111111111000) == 0b100001000000 => self.swap(),
+                    op if (op & 0b111110111000) == 0b100010000000 => self.ext(),
+                    op if (op & 0b111111000000) == 0b100001000000 => self.pea(),
                     op if (op & 0b101110000000) == 0b100010000000 => self.movem(),
                     op if (op & 0b111100000000) == 0b101000000000 => self.tst(),
+                    op if (op & 0xfc0) == 0x800 => self.nbcd(),
+                    op if (op & 0xf00) == 0 && (op >> 6) & 0b11 != 0b11 => self.negx(),
+                    op if (op & 0xf00) == 0x200 && (op >> 6) & 0b11 != 0b11 => self.clr(),
+                    op if (op & 0xf00) == 0x400 && (op >> 6) & 0b11 != 0b11 => self.neg(),
+                    op if (op & 0xf00) == 0x600 && (op >> 6) & 0b11 != 0b11 => self.not(),
+                    op if (op & 0xfc0) == 0x0c0 => self.move_from_sr(),
+                    op if (op & 0xfc0) == 0x4c0 => self.move_to_ccr(),
+                    op if (op & 0xfc0) == 0x6c0 => self.move_to_sr(),
+                    op if (op & 0xff0) == 0xe60 => self.move_usp(),
                     op if (op & 0b111000000) == 0b111000000 => self.lea(),
                     op if (op & 0b111000000) == 0b110000000 => self.chk(),
-                    _ => {}, //still a couple to be implemented but i don't know what they do
+                    _ => self.illegal(),
                 }
             }
             0b0101 => {
@@ -144,31 +320,39 @@ impl M68k {
                 }
             }
             0b0110 => self.bcc(),
-            0b0111 => {
-                //moveq
-                let reg = ((self.op >>9) & 0b111) as usize;
-                self.d[reg] = (self.op & 0xFF) as u32;
-            }
+            0b0111 => self.moveq(),
             0b1000 => {
                 // div, decimal subtraction, bitwise or
                 if(self.op >> 6) &0b11 == 0b11 {
                     self.div();
                 }
-                else if (self.op >> 4) & 0b1111 == 0b1000{
+                else if (self.op >> 4) & 0b11111 == 0b10000 {
                     self.sbcd();
                 }
                 else{ self.or(); }
             }
             0b1001 => {
-                self.sub();
+                //subtraction
+                if (self.op >> 6) & 0b11 == 0b11 {
+                    self.suba();
+                }
+                else if self.op & 0b100110000 == 0b100000000 {
+                    self.subx();
+                }
+                else { self.sub(); }
             }
             0b1011 => {
-                if (self.op & 0b100000) == 0b100000 {
-                    if(self.op &0b111) != 001 {
-                        self.eor();
-                    }
+                //comparisons and exclusive or
+                if (self.op >> 6) & 0b11 == 0b11 {
+                    self.cmpa();
+                }
+                else if self.op & 0x100 == 0 {
+                    self.cmp();
+                }
+                else if (self.op >> 3) & 0b111 == 0b001 {
+                    self.cmpm();
                 }
-                self.cmp();
+                else { self.eor(); }
             }
             0b1100 => {
                 let temp = self.op >> 4;
@@ -183,846 +367,1375 @@ impl M68k {
             }
             0b1101 => {
                 //addition
-                self.add();
-
+                if (self.op >> 6) & 0b11 == 0b11 {
+                    self.adda();
+                }
+                else if self.op & 0b100110000 == 0b100000000 {
+                    self.addx();
+                }
+                else { self.add(); }
             }
             0b1110 => {
                 //shifts and rotations
-
+                self.shift();
             }
-            _ => println!("is this an opcode? {:#018b}", self.op),		
-        } 
-        return true;
+            //lines 1010 and 1111 are unimplemented on the 68000 and trap so
+            //they can be emulated in software
+            0b1010 => self.abort(LINE_A),
+            _ => self.abort(LINE_F),
+        }
     }
 
     fn ori(&mut self) {
-        let arg = self.op;
-        let mode: u32 = 1 << ((arg >> 6) & 0b11) as u32;
-        let mut temp = 0;
-        if mode == 4 {
-            temp = self.next_l();
-        }
-        else{
-            temp = self.next_op() as u32;
-        }
-        if arg == 0x007c {
-            self.sr = self.sr | temp as u16;
-            return;
-        }
-        let reg: usize = (arg & 0b111) as usize;
-        match (arg >> 3) & 0b111 {
-            0 => {
-                //ori with d register
-                self.d[reg] = by_byte((self.d[reg] | temp), self.d[reg], mode);
+        self.logic_imm(|dst, imm| dst | imm);
+    }
+
+    fn andi(&mut self) {
+        self.logic_imm(|dst, imm| dst & imm);
+    }
+
+    //ORI, ANDI and EORI. With the immediate addressing mode as the
+    //destination these operate on the CCR if the size is byte, or on the
+    //whole SR if it's word, which is privileged. There's no long form.
+    fn logic_imm(&mut self, f: fn(u32, u32) -> u32) {
+        let size = match self.size_field() {
+            Some(size) => size,
+            None => return,
+        };
+        if self.op & 0x3f == 0b111100 {
+            if size == 4 {
+                self.illegal();
+                return;
+            }
+            if size == 2 && !self.privileged() {
+                return;
+            }
+            let imm = self.next_imm(size);
+            let res = f(self.sr as u32, imm) as u16;
+            if size == 2 {
+                self.set_sr(res);
             }
-            0b111 => {
-                //ORI with memory
-                let temp2 = self.next_op();
-                self.d[reg] = by_byte((temp | self.memory.read_l(temp2 as usize)), 
-                                      self.d[reg], mode);
+            else {
+                self.sr = (self.sr & 0xff00) | (res & 0x1f);
             }
-            _ => {}
+            return;
         }
+        let imm = self.next_imm(size);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let res = f(self.read_ea(ea, size), imm);
+        self.logic_flags(res, size);
+        self.write_ea(ea, res, size);
     }
 
-    fn andi(&mut self) {
-        let arg = self.op;
-        let temp = self.next_op();
-        if arg == 0x027c {
-
-            return;
+    //AND, OR and EOR. Bit 8 is clear for <ea> op Dn -> Dn and set for
+    //Dn op <ea> -> <ea>, and EOR only has the second form.
+    fn logic(&mut self, f: fn(u32, u32) -> u32) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let modes = if self.op & 0x100 == 0 {
+            EA_DATA
         }
-        let mode: u32 = 1 << ((arg >> 6) & 0b11) as u32;
-        let reg: usize = (arg & 0b111) as usize;
-        match (arg >> 3) & 0b111 {
-            0 => {
-                self.d[reg] = by_byte((self.d[reg] & temp as u32), self.d[reg], mode);
-            }
-            0b111 => {
-                //andi with memory
-                let temp2 = self.next_op();
-                self.d[reg] = by_byte((temp as u32 & self.memory.read_l(temp2 as usize)), self.d[reg], mode);
-            }
-            _ => {}
+        else if self.op >> 12 == 0b1011 {
+            EA_DATA | EA_ALTERABLE //EOR
         }
+        else {
+            EA_MEMORY | EA_ALTERABLE
+        };
+        let ea = self.decode_ea(self.op & 0x3f, size, modes);
+        let res = f(self.read_ea(ea, size), self.d[reg] & mask(size));
+        self.logic_flags(res, size);
+        if self.op & 0x100 == 0 {
+            self.d[reg] = by_byte(res, self.d[reg], size);
+        }
+        else {
+            self.write_ea(ea, res, size);
+        }
+    }
+
+    //The logical ops, and the moves and tests that behave like them, set N
+    //and Z from the result and always clear V and C.
+    fn logic_flags(&mut self, res: u32, size: u32) {
+        self.set_flag(CARRY | OVERFLOW, false);
+        self.set_flag(NEGATIVE, res & msb(size) != 0);
+        self.set_flag(ZERO, res & mask(size) == 0);
     }
 
     fn subi(&mut self) {
-        let arg = self.op;
-        let temp = self.next_op();
-        let reg: usize = (arg & 0b111) as usize;
-        let  mode = 1 << ((arg >> 6) & 0b11);
-        match (arg >> 3) & 0b111 {
-            0 => {
-                let res =
-                    (self.d[reg] as i32 - (by_byte(temp as u32, 0, mode) as i32));
-                self.d[reg] = by_byte(res as u32, self.d[reg], mode);
-            }
-            0b111 => {
-                //subi with memory
-                let temp2 = self.next_op();
-                let mut res = by_byte(self.memory.read_l(temp2 as usize), 0, mode);
-                res = (self.d[reg] as i32 - res as i32) as u32;
-                self.d[reg] = by_byte(res, self.d[reg], mode);
+        let size = match self.size_field() {
+            Some(size) => size,
+            None => return,
+        };
+        let imm = self.next_imm(size);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let dst = self.read_ea(ea, size);
+        let res = self.sub_op(imm, dst, 0, size);
+        self.write_ea(ea, res, size);
+    }
+
+    //Shared front end for the bit operations. Gets the bit number, either
+    //from an immediate word or, if bit 8 is set, from the d register in bits
+    //11-9. Then decodes the operand, in one of the given modes, and sets Z
+    //if the bit was clear. Data registers are operated on as longs and
+    //everything else as bytes, so the bit number is taken mod 32 or mod 8
+    //accordingly. Returns the operand, its size, its old value and the mask
+    //for the selected bit so the caller can modify it.
+    fn bit_op(&mut self, modes: u8) -> (Ea, u32, u32, u32) {
+        let bitnum = if self.op & 0x100 != 0 {
+            self.d[((self.op >> 9) & 0b111) as usize]
+        }
+        else {
+            self.next_op() as u32
+        };
+        let ea = self.decode_ea(self.op & 0x3f, 1, modes);
+        if let Ea::Imm(_) = ea {
+            //BTST can test an immediate, but only with the bit number in Dn
+            if self.op & 0x100 == 0 {
+                self.invalid_ea();
             }
-            _ => {}
         }
+        let size = if let Ea::D(_) = ea { 4 } else { 1 };
+        let data = self.read_ea(ea, size);
+        let mask = 1 << (bitnum % (size * 8));
+        self.set_flag(ZERO, data & mask == 0);
+        (ea, size, data, mask)
     }
 
     fn btst(&mut self){
-        let bitnum = self.next_op() as u32;
-        let reg = (self.op & 0b111) as usize;
-        match (self.op >> 3) & 0b111 {//finding source
-            0 => {//data register
-                let mask = 2_u32.pow(bitnum % 32);
-                if(self.d[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            1 => {//A register
-                let mask = 2_u32.pow(bitnum % 32);
-                if(self.a[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            2 => {//address from A reg
-                let mask = 2_u8.pow(bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            3 => {//A(n) with increment
-                let mask = 2_u8.pow(bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.a[reg] += 1;
-            }
-            4 => {//A(n) with decrement
-                let mask = 2_u8.pow(bitnum % 7);
-                self.a[reg] -= 1;
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            _ => println!("invalid addressing mode for BTSTZ")
-
-        }
+        self.bit_op(EA_DATA);
     }
 
     fn bchg(&mut self){
-        let bitnum = self.next_op() as u32;
-        let reg = (self.op & 0b111) as usize;
-        match (self.op >> 3) & 0b111 {//finding source
-            0 => {//data register
-                let mask = 1 << (bitnum % 32);
-                if(self.d[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.d[reg] = self.d[reg] ^ mask;
-            }
-            1 => {//A register
-                let mask = 1 << (bitnum % 32);
-                if(self.a[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.a[reg] = self.a[reg] ^ mask;
-            }
-            2 => {//address from A reg
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp ^ mask) as u32, 1);
-            }
-            3 => {//A(n) with increment
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp ^ mask)as u32, 1);
-                self.a[reg] += 1;
-            }
-            4 => {//A(n) with decrement
-                let mask = 1 << (bitnum % 7);
-                self.a[reg] -= 1;
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp ^ mask)as u32, 1);
-            }
-            _ => {println!("invalid addressing mode for BCHGZ");}
-
-        }
+        let (ea, size, data, mask) = self.bit_op(EA_DATA | EA_ALTERABLE);
+        self.write_ea(ea, data ^ mask, size);
     }
 
     fn bclr(&mut self){
-        let bitnum = self.next_op() as u32;
-        let reg = (self.op & 0b111) as usize;
-        match (self.op >> 3) & 0b111 {//finding source
-            0 => {//data register
-                let mask = 2_u32.pow(bitnum % 32);
-                if(self.d[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.d[reg] = self.d[reg] & !mask;
-            }
-            1 => {//A register
-                let mask = 1 << (bitnum % 32);
-                if(self.a[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.a[reg] = self.a[reg] & (!mask as u32);
-            }
-            2 => {//address from A reg
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp & !mask) as u32, 1);
-            }
-            3 => {//A(n) with increment
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp & !mask)as u32, 1);
-                self.a[reg] += 1;
-            }
-            4 => {//A(n) with decrement
-                let mask = 1 << (bitnum % 7);
-                self.a[reg] -= 1;
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.memory.mem_write(addr as usize, (temp & !mask)as u32, 1);
-            }
-            _ => {println!("invalid addressing mode for BCLRZ");}
-        }
-
+        let (ea, size, data, mask) = self.bit_op(EA_DATA | EA_ALTERABLE);
+        self.write_ea(ea, data & !mask, size);
     }
 
-
     fn bset(&mut self){
-        let bitnum = self.next_op() as u32;
-        let reg = (self.op & 0b111) as usize;
-        match (self.op >> 3) & 0b111 {//finding source
-            0 => {//data register
-                let mask = 1 << (bitnum % 32);
-                if(self.d[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.d[reg] = self.d[reg] | mask;
-            }
-            1 => {//A register
-                let mask = 1 << (bitnum % 32);
-                if(self.a[reg] & mask) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.a[reg] = self.a[reg] | mask;
-            }
-            2 => {//address from A reg
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            3 => {//A(n) with increment
-                let mask = 1 << (bitnum % 7);
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-                self.a[reg] += 1;
-            }
-            4 => { //A(n) with decrement
-                let mask = 1 << (bitnum % 7)as u32;
-                self.a[reg] -= 1;
-                let addr = self.a[reg];
-                let temp = self.memory.read_b(addr as usize);
-                if(temp & mask as u8) == 0 {
-                    self.sr = self.sr | 0b000000000000000000100;
-                }
-                else {
-                    self.sr = self.sr & 0b111111111111111111011;
-                }
-            }
-            _ => {println!("invalid addressing mode for op: BSETZ");}
-
-        }
+        let (ea, size, data, mask) = self.bit_op(EA_DATA | EA_ALTERABLE);
+        self.write_ea(ea, data | mask, size);
     }
 
+    //Traps if Dn is below 0 or above the upper bound, both as signed words.
+    //N says which end it was out of.
     fn chk(&mut self){
-
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA);
+        let bound = sign_extend(self.read_ea(ea, 2), 2) as i32;
+        let data = sign_extend(self.d[reg], 2) as i32;
+        if data < 0 {
+            self.set_flag(NEGATIVE, true);
+            self.exception(CHK);
+        }
+        else if data > bound {
+            self.set_flag(NEGATIVE, false);
+            self.exception(CHK);
+        }
     }
 
+    //MOVEP transfers a word or long one byte at a time to every other
+    //address, starting at d16(An), highest byte first. This lines up with
+    //8-bit peripherals that are only wired to one half of the data bus.
+    //Bit 7 set is register to memory, and bit 6 set is long.
     fn movep(&mut self){
         let reg = ((self.op >> 9) & 0b111) as usize;
         let areg = (self.op & 0b111) as usize; //what address to use
-        let addr = (self.next_op() as u32) + self.a[areg]; //the displacement to add
-        if self.op & 0b10000000 != 0 {//FROM memory, TO d reg
-            if self.op & 0b1000000 != 0 {//long
-                self.d[reg] = self.memory.read_l(addr as usize);
-            }
-            else{//word
-                self.d[reg] = by_byte(self.memory.read_w(addr as usize) as u32, self.d[reg], 2);
+        let disp = sign_extend(self.next_op() as u32, 2);
+        let addr = self.a[areg].wrapping_add(disp);
+        let size = if self.op & 0b1000000 != 0 { 4 } else { 2 };
+        let mut data = 0;
+        for i in 0..size {
+            let byte_addr = addr.wrapping_add(2 * i);
+            let shift = 8 * (size - 1 - i);
+            if self.op & 0b10000000 != 0 {//FROM d reg, TO memory
+                let byte = self.d[reg] >> shift;
+                self.write_mem(byte_addr, byte, 1);
+            }
+            else {//FROM memory, TO d reg
+                data |= self.read_mem(byte_addr, 1) << shift;
             }
         }
-        else {//FROM d reg, TO memory
-            if self.op & 0b1000000 != 0 {//long
-                self.memory.mem_write(addr as usize, self.d[reg], 4);
-            }
-            else {
-                self.memory.mem_write(addr as usize, self.d[reg], 2);
-            }
+        if self.op & 0b10000000 == 0 {
+            self.d[reg] = by_byte(data, self.d[reg], size);
         }
-
     }
 
+    //The register list comes before any extension words for the EA. Bit 0
+    //of the list is D0 and bit 15 is A7, except for -(An) where the order is
+    //reversed, since the registers are stored from A7 down to D0. Words
+    //loaded into registers are sign extended, data registers included.
+    //Registers can be stored with -(An) or a control mode that can be
+    //written, and loaded with (An)+ or any control mode.
     fn movem(&mut self){
-
-    }
-
-    fn addi(&mut self) {
-        let arg = self.op;
-        let temp = self.next_op();
-        let reg: usize = (arg & 0b111) as usize;
-        let mut mode = 0;
-        match (arg >> 3) & 0b111 {
-            0 => {
-                let res = (self.d[reg] as i32 + temp as i32);
-                match arg >> 6 & 0b11 {
-                    0b00 => {
-                        let check = (res & 0xff) as i8;
-                        mode = 1;
-                        if(res > 0xff){ 
-                            self.sr = self.sr | 1;
-                            self.sr = self.sr | 0b10;
-
-                        }
-                    }
-                    0b01 => {
-                        mode = 2;
-                        let check = (res & 0xffff) as i16;
+        let list = self.next_op();
+        let size = if self.op & 0x40 != 0 { 4 } else { 2 };
+        let to_regs = self.op & 0x400 != 0;
+        let reg = (self.op & 0b111) as usize;
+        match (self.op >> 3) & 0b111 {
+            4 if !to_regs => {
+                //An is only updated once everything is stored, so if it's
+                //in the list its original value is the one written
+                let mut addr = self.a[reg];
+                for i in 0..16 {
+                    if list & (1 << i) != 0 {
+                        addr = addr.wrapping_sub(size);
+                        let data = self.reg(15 - i);
+                        self.write_mem(addr, data, size);
                     }
-                    0b10 => {
-                        let check = (res < temp as i32) | (res < self.d[reg] as i32);
+                }
+                self.a[reg] = addr;
+            }
+            3 if to_regs => {
+                let mut addr = self.a[reg];
+                for i in 0..16 {
+                    if list & (1 << i) != 0 {
+                        let data = sign_extend(self.read_mem(addr, size), size);
+                        self.set_reg(i, data);
+                        addr = addr.wrapping_add(size);
                     }
-                    _ => {}
                 }
-                self.d[reg] = by_byte(res as u32, self.d[reg], mode);
+                self.a[reg] = addr;
             }
-            0b111 => {//fix this
+            _ => {
+                let modes = if to_regs { EA_CONTROL } else { EA_CONTROL | EA_ALTERABLE };
+                if let Ea::Mem(mut addr) = self.decode_ea(self.op & 0x3f, size, modes) {
+                    for i in 0..16 {
+                        if list & (1 << i) != 0 {
+                            if to_regs {
+                                let data = sign_extend(self.read_mem(addr, size), size);
+                                self.set_reg(i, data);
+                            }
+                            else {
+                                let data = self.reg(i);
+                                self.write_mem(addr, data, size);
+                            }
+                            addr = addr.wrapping_add(size);
+                        }
+                    }
+                }
             }
-            _ => {}
         }
     }
 
-    fn eori(&mut self) {
-        let arg = self.op;
-        let temp = self.next_op();
-        if arg == 0x0A7c {
-            return;
+    //Registers numbered the way register lists and extension words number
+    //them: 0-7 are D0-D7 and 8-15 are A0-A7.
+    fn reg(&self, num: usize) -> u32 {
+        if num < 8 { self.d[num] } else { self.a[num - 8] }
+    }
+
+    fn set_reg(&mut self, num: usize, data: u32) {
+        if num < 8 {
+            self.d[num] = data;
         }
-        let mode = 2_u32.pow(((arg >> 6) & 0b11) as u32);
-        let reg: usize = (arg & 0b111) as usize;
-        match (arg >> 3) & 0b111 {
-            0 => {
-                self.d[reg] = by_byte((self.d[reg] ^ temp as u32), self.d[reg], mode);
-            }
-            0b111 => {
-                let temp2 = self.next_op();
-                let temp3 = self.memory.read_l(temp2 as usize) ^ (temp as u32);
-                self.memory.mem_write(temp2 as usize, temp3, mode);
-            }
-            _ => {}
+        else {
+            self.a[num - 8] = data;
         }
     }
 
+    fn addi(&mut self) {
+        let size = match self.size_field() {
+            Some(size) => size,
+            None => return,
+        };
+        let imm = self.next_imm(size);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let dst = self.read_ea(ea, size);
+        let res = self.add_op(imm, dst, 0, size);
+        self.write_ea(ea, res, size);
+    }
+
+    fn eori(&mut self) {
+        self.logic_imm(|dst, imm| dst ^ imm);
+    }
+
     fn cmpi(&mut self) {
-        let arg = self.op;
-        let mut mode = 0;
-        let mut temp: u32 = 0;
-        let mut temp2: u32 = 0;
-        match ((arg >> 6) & 0b11) {
-            // finding size of operation - mode is set according to the standard used by other ops
-            0 => { 
-                //byte
-                mode = 1;
-                temp = self.next_op() as u32;
-            } 
-            0b01 => {
-                //word
-                mode = 2; 
-                temp = self.next_op() as u32;			
-            } 
-            0b10 => {
-                //long
-                mode = 4; 
-                temp = self.next_l();
-            } 
-            _ => {}
+        let size = match self.size_field() {
+            Some(size) => size,
+            None => return,
+        };
+        let imm = self.next_imm(size);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let dst = self.read_ea(ea, size);
+        self.cmp_op(imm, dst, size);
+    }
+
+    fn mov(&mut self) {
+        let size = match (self.op >> 12) & 0b11 {
+            0b01 => 1,
+            0b11 => 2,
+            _ => 4,
+        };
+        let src = self.decode_ea(self.op & 0x3f, size, EA_ANY);
+        let data = self.read_ea(src, size);
+        //the destination field has its mode and register halves swapped
+        let field = ((self.op >> 3) & 0b111000) | ((self.op >> 9) & 0b111);
+        let dst = self.decode_ea(field, size, EA_ALTERABLE);
+        //MOVEA is the same encoding with an address register destination,
+        //but it sign extends words and doesn't touch the flags
+        if let Ea::D(_) | Ea::Mem(_) = dst {
+            self.logic_flags(data, size);
         }
+        self.write_ea(dst, data, size);
+    }
 
+    //Reading the SR isn't privileged on the 68000, only writing it is.
+    fn move_from_sr(&mut self) {
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA | EA_ALTERABLE);
+        let sr = self.sr as u32;
+        self.write_ea(ea, sr, 2);
     }
 
-    fn mov(&mut self) {
-        let arg = self.op;
-        let mut source: u32 = 0;
-        let mut mode = 0;
-        let reg = (arg & 0b111) as usize;
-        match (arg >> 12) & 0b11 {
-            0b01 => mode = 1,
-            0b11 => mode = 2,
-            _ => mode = 4,
-        }
-        match ((arg >> 3) & 0b111) {
-            //finding source
-            0 => {
-                //source is a d register
-                source = self.d[reg];
-            }
-            0b001 => {
-                //source is an a reg
-                source = self.a[reg];
-            }
-            0b010 => {
-                //source is an address in an a reg
-                source = self.memory.read_l(self.a[reg] as usize);
-            }
-            0b011 => {
-                //source is an address in an a reg with post+
-                source = self.memory.read_l(self.a[reg] as usize);
-                self.a[reg] += mode;
-            }
-            0b100 => {
-                //source is a reg with pre-
-                self.a[reg] -= mode;
-                source = self.memory.read_l(self.a[reg] as usize);
-            }
-            0b101 => {
-                //source is an address with displacement
-                let addr = (self.a[reg] + self.next_op() as u32) as usize;
-                source = self.memory.read_l(addr);
-            }
-            0b110 => {
-                //source is an address with index
-                let temp = self.next_op();
-                let mut addr = (self.a[reg] + (temp & 0xff) as u32) as usize;
-                if (temp & 0x8000) != 0 {
-                    addr += self.a[((temp >> 12) & 0b111) as usize] as usize;
-                }
-                else {
-                    addr += self.d[((temp >> 12) & 0b111) as usize] as usize;
-                }
-                source = self.memory.read_l(addr);
-            }
-            0b111 => {
-                //source is immediate
-                match ((arg >> 12) & 0b11) {
-                    0b10 => {
-                        //longword immediate
-                        source = ((self.next_op() as u32) << 16) + self.next_op() as u32;
-                    }
-                    //word and byte immediates are both coded as 16 bits, so they're
-                    //treated as the same case here
-                    _ => {
-                        source = self.next_op() as u32;
-                    }
-                }
-            }
-            _ => {
-                println!("uncaught move source");
-            }
+    //The source is a word, but only its low byte ends up in the CCR.
+    fn move_to_ccr(&mut self) {
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA);
+        let data = self.read_ea(ea, 2) as u16;
+        self.sr = (self.sr & 0xff00) | (data & 0x1f);
+    }
+
+    fn move_to_sr(&mut self) {
+        if !self.privileged() {
+            return;
         }
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA);
+        let data = self.read_ea(ea, 2) as u16;
+        self.set_sr(data);
+    }
 
-        match ((arg >> 6) & 0b111) {
-            //finding dest
-            
-            0 => {
-                //dest is a d register
-                self.d[((arg >> 9) & 0b111) as usize] = source;
-            }
-            0b111 => {
-                //dest is memory
-                let mut mode = 0;
-                match (arg >> 12) {
-                    0b01 => mode = 1,
-                    0b11 => mode = 2,
-                    0b10 => mode = 4,
-                    _ => {}
-                }
-                let temp = self.next_op() as usize;
-                self.memory.mem_write(temp, source, mode);
-            }
-            0b001 => {
-                //dest is an a reg
-                self.a[((arg >> 9) & 0b111) as usize] = source;
-            }
-            _ => {
-                println!("uncaught move dest");
-            }
+    //Bit 3 set copies the USP to An, clear copies An to the USP.
+    fn move_usp(&mut self) {
+        if !self.privileged() {
+            return;
+        }
+        let reg = (self.op & 0b111) as usize;
+        if self.op & 0b1000 != 0 {
+            self.a[reg] = self.usp;
+        }
+        else {
+            self.usp = self.a[reg];
         }
     }
 
-    fn abcd(&mut self) {}
-    fn and(&mut self) {}
+    fn moveq(&mut self) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        self.d[reg] = sign_extend(self.op as u32, 1);
+        self.logic_flags(self.d[reg], 4);
+    }
+
+    fn abcd(&mut self) {
+        let (src, dst) = self.x_operands(1);
+        let data = self.read_ea(src, 1);
+        let data2 = self.read_ea(dst, 1);
+        let res = self.bcd_add(data, data2);
+        self.write_ea(dst, res, 1);
+    }
+    fn and(&mut self) {
+        self.logic(|src, dst| src & dst);
+    }
 
+    //Pops the SR and PC that an exception pushed. Both are popped off the
+    //supervisor stack before the new SR is loaded, since that might switch
+    //back to the user stack.
     fn rte(&mut self) {
-        let SR = self.memory.read_l(self.a[7] as usize);
-        self.a[7] -= 4;
-        let PC = self.memory.read_l(self.a[7] as usize);
-        self.a[7] -= 4;
+        if !self.privileged() {
+            return;
+        }
+        let sr = self.pop(2) as u16;
+        self.pc = self.pop(4);
+        self.set_sr(sr);
     }
 
+    //Like RTE, but only the condition codes are taken from the popped word,
+    //so it isn't privileged.
     fn rtr(&mut self) {
-        println!("not sure how this is different from RTE");
-        self.rte();
+        let ccr = self.pop(2) as u16;
+        self.sr = (self.sr & 0xff00) | (ccr & 0x1f);
+        self.pc = self.pop(4);
     }
+    //Used for the ILLEGAL opcode and anything else that doesn't decode.
     fn illegal(&mut self) {
-        println!("this is a type of trap: the PC and SR are pushed to stack, and can be retrieved with RTE");
-        self.a[7] -= 4;
-        self.memory.mem_write(self.a[7] as usize, self.pc, 4);
-        self.a[7] -= 4;
-        self.memory.mem_write(self.a[7] as usize, self.sr as u32, 4);
+        self.abort(ILLEGAL_INSTRUCTION);
     }
 
+    //Loads the SR and then does nothing until an interrupt above the new
+    //mask comes in. The PC is already past the immediate, so that's where
+    //the interrupt returns to.
     fn stop(&mut self) {
-        if self.sr & 0x8000 != 0 {
-            self.sr = self.next_op();
-        } else {
-            self.trap();
+        if !self.privileged() {
+            return;
         }
+        let sr = self.next_op();
+        self.set_sr(sr);
+        self.stopped = true;
     }
 
     fn rts(&mut self) {
-        self.pc = self.memory.read_l(self.a[7] as usize);
-        self.a[7] += 4;
+        self.pc = self.pop(4);
     }
 
     fn unlk(&mut self) {
-        let arg = self.op & 0b111;
-        self.a[7] = self.a[arg as usize];
-        let temp = self.memory.read_w(self.a[arg as usize] as usize) as u32;
-        self.a[arg as usize] = temp;
+        let reg = (self.op & 0b111) as usize;
+        self.a[7] = self.a[reg];
+        self.a[reg] = self.pop(4);
     }
 
+    //Pushes An, points it at the new frame, and then moves the stack
+    //pointer by a signed displacement, usually negative to make room for
+    //locals.
     fn link(&mut self) {
-        let arg = self.op & 0b111;
-        self.a[7] -= 4;
-        self.memory
-            .mem_write(self.a[7] as usize, self.a[arg as usize], 4);
-        self.a[arg as usize] = self.a[7];
-        self.a[7] += self.next_op() as u32;
+        let reg = (self.op & 0b111) as usize;
+        let disp = sign_extend(self.next_op() as u32, 2);
+        let data = self.a[reg];
+        self.push(data, 4);
+        self.a[reg] = self.a[7];
+        self.a[7] = self.a[7].wrapping_add(disp);
     }
 
     fn swap(&mut self) {
         let arg = self.op & 0b111;
         let temp = self.d[arg as usize].rotate_left(16);
         self.d[arg as usize] = temp;
+        self.logic_flags(temp, 4);
     }
 
     fn trap(&mut self) {
-        self.sr = self.sr | 0x8000;
-        let arg = self.op & 0xf;
-        println!("Call to trap # {}", arg);
+        let arg = (self.op & 0xf) as u32;
+        self.exception(TRAP_BASE + arg);
     }
 
     fn trapv(&mut self) {
-
+        if self.sr & OVERFLOW != 0 {
+            self.exception(TRAPV);
+        }
     }
 
     fn jmp(&mut self) {
-        let arg = self.op & 0b111111;
-        match arg & 0b111000 {
-            0 => {
-                //jump to address in an A reg
-                self.pc = self.a[(arg & 0b111) as usize];
-            }
-            0b111000 => {
-                //jump to imm address
-                match arg & 0b111 {
-                    0b001 => {
-                        //jump to long word
-                        self.pc = ((self.next_op() as u32) << 16) 
-                            + self.next_op() as u32;
-                    }
-                    0b000 => {
-                        //jump to word
-                        self.pc = (self.next_op() as u32);
-                    }
-                    _ => {}
-                }
-            }
-            _ => {}
+        if let Ea::Mem(addr) = self.decode_ea(self.op & 0x3f, 4, EA_CONTROL) {
+            self.pc = addr;
         }
     }
 
     fn jsr(&mut self) {
-        self.a[7] -= 4;
-        self.memory.mem_write(self.a[7] as usize, self.pc, 4);
-        self.jmp();
+        //the target has to be decoded first so the pushed return address is
+        //past any extension words
+        if let Ea::Mem(addr) = self.decode_ea(self.op & 0x3f, 4, EA_CONTROL) {
+            let pc = self.pc;
+            self.push(pc, 4);
+            self.pc = addr;
+        }
     }
 
+    //Test and set: sets the flags from a byte, then sets its top bit. On
+    //the real bus this is one indivisible read-modify-write cycle.
     fn tas(&mut self) {
-        let temp = self.next_op() as usize;
-        if (self.memory.read_b(temp) == 0) {
-            self.memory.mem_write(temp, 80, 1);
-        }
+        let ea = self.decode_ea(self.op & 0x3f, 1, EA_DATA | EA_ALTERABLE);
+        let data = self.read_ea(ea, 1);
+        self.logic_flags(data, 1);
+        self.write_ea(ea, data | 0x80, 1);
     }
 
     fn pea(&mut self) {
-
+        if let Ea::Mem(addr) = self.decode_ea(self.op & 0x3f, 4, EA_CONTROL) {
+            self.push(addr, 4);
+        }
     }
 
+    //EXT.W sign extends the low byte of Dn to a word, and EXT.L the low
+    //word to a long.
     fn ext(&mut self) {
-        println!("ext");
+        let reg = (self.op & 0b111) as usize;
+        let size = if self.op & 0x40 != 0 { 4 } else { 2 };
+        let res = sign_extend(self.d[reg], size / 2);
+        self.d[reg] = by_byte(res, self.d[reg], size);
+        self.logic_flags(res, size);
     }
 
     fn tst(&mut self) {
-        println!("tst");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let data = self.read_ea(ea, size);
+        self.logic_flags(data, size);
     }
 
     fn not(&mut self) {
-        println!("not");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let res = !self.read_ea(ea, size) & mask(size);
+        self.logic_flags(res, size);
+        self.write_ea(ea, res, size);
     }
 
     fn neg(&mut self) {
-        println!("neg");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let data = self.read_ea(ea, size);
+        let res = self.sub_op(data, 0, 0, size);
+        self.write_ea(ea, res, size);
+    }
+
+    fn negx(&mut self) {
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        let data = self.read_ea(ea, size);
+        let zero = self.sr & ZERO != 0;
+        let res = self.sub_op(data, 0, self.extend(), size);
+        if res == 0 {
+            self.set_flag(ZERO, zero);
+        }
+        self.write_ea(ea, res, size);
     }
 
     fn clr(&mut self) {
-        println!("clr");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_DATA | EA_ALTERABLE);
+        self.write_ea(ea, 0, size);
+        self.logic_flags(0, size);
     }
 
     fn lea(&mut self) {
-        println!("lea");
+        if let Ea::Mem(addr) = self.decode_ea(self.op & 0x3f, 4, EA_CONTROL) {
+            self.a[((self.op >> 9) & 0b111) as usize] = addr;
+        }
     }
 
     fn scc(&mut self) {
         let mut to_write: u32 = 0;
         if condition((self.op >> 8) & 0xf, self.sr) {
-            to_write = 0xffffffff;
+            to_write = 0xff;
         }
-        let temp = (self.op & 0b111) as usize;
-        match (self.op >> 3) & 0b111 {
-            0 => { // Dn - setting a d reg
-                self.d[temp] = to_write;
-            },
-            1 => {} //invalid addressing mode for this op
-            2 => {// (An) - setting memory from an a register
-                let addr = self.a[temp] as usize;
-                self.memory.mem_write(addr, to_write, 1);
-            }
-            3 => { // (An)+ - same as above but with increment
-                let addr = self.a[temp] as usize;
-                self.memory.mem_write(addr, to_write, 1);
-                self.a[temp] += 1;
-            }
-            4 => { // -(An) - decrement
-                self.a[temp] -= 1;
-                let addr = self.a[temp] as usize;
-                self.memory.mem_write(addr, to_write, 1);
-            }
-            5 => { // (An + d) - memory address with offset
-                let mut addr = self.a[temp] as usize;
-                addr += self.next_op() as usize;
-                self.memory.mem_write(addr, to_write, 1);
-            }
-            6 => { // i need to figure out how indexing works
-
-            }
-            7 => {//some misc cases
-                match temp {
-                    0 => { //absolute near
-                        let addr = self.next_op() as usize;
-                        self.memory.mem_write(addr, to_write, 1);
-                    }
-                    1 => {
-                        let addr = self.next_l() as usize;
-                        self.memory.mem_write(addr, to_write, 1);
-                    }
-                    _ => {} //invalid addressing modes for this instruction
-
-                }
-            }
-            _ => { /*shouldnt ever get here */ }
-        }      
+        let ea = self.decode_ea(self.op & 0x3f, 1, EA_DATA | EA_ALTERABLE);
+        self.write_ea(ea, to_write, 1);
     }
 
     fn subq(&mut self) {
-        println!("subq");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let data = quick_data(self.op);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ALTERABLE);
+        if let Ea::A(reg) = ea {
+            //same as ADDQ, address registers are whole and flags are kept
+            self.a[reg] = self.a[reg].wrapping_sub(data);
+            return;
+        }
+        let dst = self.read_ea(ea, size);
+        let res = self.sub_op(data, dst, 0, size);
+        self.write_ea(ea, res, size);
     }
 
     fn addq(&mut self) {
-        println!("addq");
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let data = quick_data(self.op);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ALTERABLE);
+        if let Ea::A(reg) = ea {
+            //address registers are always added to whole, and the flags
+            //are left alone
+            self.a[reg] = self.a[reg].wrapping_add(data);
+            return;
+        }
+        let dst = self.read_ea(ea, size);
+        let res = self.add_op(data, dst, 0, size);
+        self.write_ea(ea, res, size);
     }
 
+    //BRA, BSR and the conditional branches. Condition 0 is BRA and 1, which
+    //would be "never", is BSR. The displacement is relative to the word
+    //after the opcode, and is in the low byte, or in an extension word if
+    //the low byte is 0.
     fn bcc(&mut self) {
-        let arg = self.op;
-        let mut offset = 0 as u16;
-        match arg & 0b11111111 {
-            0 => {
-                offset = self.next_op();
-            } // 16 bit offset
-            _ => offset = arg & 0b11111111, //8 bit offset
-        }
+        let base = self.pc;
+        let disp = match self.op & 0xff {
+            0 => sign_extend(self.next_op() as u32, 2),
+            disp => sign_extend(disp as u32, 1),
+        };
         let check = (self.op >> 8) & 0xf;
         if check == 1 {
-            self.a[7] -= 4;
-            let temp = self.memory.read_l(self.a[7] as usize);
-            self.pc += offset as u32;
+            let pc = self.pc;
+            self.push(pc, 4);
+            self.pc = base.wrapping_add(disp);
         }
-        else if(condition((self.op >> 8) & 0xf, self.sr)){
-            self.pc += offset as u32;
+        else if condition(check, self.sr) {
+            self.pc = base.wrapping_add(disp);
         }
     }
 
+    //The loop primitive. If the condition is true it falls through,
+    //otherwise the low word of Dn is decremented and the branch is taken
+    //unless the counter has just gone past 0 to -1. The displacement is
+    //relative to the extension word.
     fn dbcc(&mut self) {
-
+        let base = self.pc;
+        let disp = sign_extend(self.next_op() as u32, 2);
+        if condition((self.op >> 8) & 0xf, self.sr) {
+            return;
+        }
+        let reg = (self.op & 0b111) as usize;
+        let count = self.d[reg].wrapping_sub(1) & 0xffff;
+        self.d[reg] = by_byte(count, self.d[reg], 2);
+        if count != 0xffff {
+            self.pc = base.wrapping_add(disp);
+        }
     }
 
+    //DIVU and DIVS divide a long in Dn by a word, leaving the quotient in
+    //the low word and the remainder in the high word. If the quotient
+    //doesn't fit in a word V is set and Dn is left as it was.
     fn div(&mut self) {
-
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA);
+        let src = self.read_ea(ea, 2);
+        if src == 0 {
+            self.exception(ZERO_DIVIDE);
+            return;
+        }
+        self.set_flag(CARRY, false);
+        let (quot, rem) = if self.op & 0x100 != 0 {
+            //DIVS, done in 64 bits so that 0x80000000 / -1 doesn't overflow
+            let dividend = self.d[reg] as i32 as i64;
+            let divisor = src as u16 as i16 as i64;
+            let quot = dividend / divisor;
+            if quot != quot as i16 as i64 {
+                self.set_flag(OVERFLOW, true);
+                return;
+            }
+            (quot as u32, (dividend % divisor) as u32)
+        }
+        else {
+            let quot = self.d[reg] / src;
+            if quot > 0xffff {
+                self.set_flag(OVERFLOW, true);
+                return;
+            }
+            (quot, self.d[reg] % src)
+        };
+        self.d[reg] = (rem << 16) | (quot & 0xffff);
+        self.set_flag(OVERFLOW, false);
+        self.set_flag(NEGATIVE, quot & 0x8000 != 0);
+        self.set_flag(ZERO, quot & 0xffff == 0);
     }
 
     fn suba(&mut self) {
-        println!("suba");
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = if self.op & 0x100 != 0 { 4 } else { 2 };
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ANY);
+        let src = sign_extend(self.read_ea(ea, size), size);
+        self.a[reg] = self.a[reg].wrapping_sub(src);
     }
 
+    //MULU and MULS multiply the low words of Dn and the source into all
+    //32 bits of Dn.
     fn mul(&mut self) {
-        println!("mul");
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let ea = self.decode_ea(self.op & 0x3f, 2, EA_DATA);
+        let src = self.read_ea(ea, 2);
+        let dst = self.d[reg] & 0xffff;
+        let res = if self.op & 0x100 != 0 {
+            (sign_extend(src, 2) as i32).wrapping_mul(sign_extend(dst, 2) as i32) as u32
+        }
+        else {
+            src * dst
+        };
+        self.d[reg] = res;
+        self.set_flag(CARRY | OVERFLOW, false);
+        self.set_flag(NEGATIVE, res & 0x80000000 != 0);
+        self.set_flag(ZERO, res == 0);
     }
 
+    //The opmode in bits 7-3 picks two data registers, two address registers,
+    //or a data register (Rx) and an address register (Ry).
     fn exg(&mut self) {
-        println!("exg");
+        let rx = ((self.op >> 9) & 0b111) as usize;
+        let ry = (self.op & 0b111) as usize;
+        let (x, y) = match (self.op >> 3) & 0b11111 {
+            0b01000 => (rx, ry),
+            0b01001 => (rx + 8, ry + 8),
+            _ => (rx, ry + 8),
+        };
+        let temp = self.reg(x);
+        let temp2 = self.reg(y);
+        self.set_reg(x, temp2);
+        self.set_reg(y, temp);
     }
 
     fn adda(&mut self) {
-        println!("adda");
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = if self.op & 0x100 != 0 { 4 } else { 2 };
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ANY);
+        let src = sign_extend(self.read_ea(ea, size), size);
+        self.a[reg] = self.a[reg].wrapping_add(src);
     }
-    
+
     fn add(&mut self) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = 1 << ((self.op >> 6) & 0b11);
+        //the <ea> can only be a destination if it's in memory
+        let modes = if self.op & 0x100 == 0 { EA_ANY } else { EA_MEMORY | EA_ALTERABLE };
+        let ea = self.decode_ea(self.op & 0x3f, size, modes);
+        let data = self.read_ea(ea, size);
+        let res = self.add_op(data, self.d[reg] & mask(size), 0, size);
+        if self.op & 0x100 == 0 {
+            //<ea> + Dn -> Dn
+            self.d[reg] = by_byte(res, self.d[reg], size);
+        }
+        else {
+            //Dn + <ea> -> <ea>
+            self.write_ea(ea, res, size);
+        }
+    }
+
+    fn addx(&mut self) {
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let (src, dst) = self.x_operands(size);
+        let data = self.read_ea(src, size);
+        let data2 = self.read_ea(dst, size);
+        let zero = self.sr & ZERO != 0;
+        let res = self.add_op(data, data2, self.extend(), size);
+        //Z is only ever cleared, so multi-precision adds can test the
+        //whole result
+        if res == 0 {
+            self.set_flag(ZERO, zero);
+        }
+        self.write_ea(dst, res, size);
+    }
 
+    //ADDX, SUBX, ABCD and SBCD all have the same two operand forms, Dy,Dx
+    //and -(Ay),-(Ax), picked by bit 3. Returns the source and destination.
+    fn x_operands(&mut self, size: u32) -> (Ea, Ea) {
+        let rx = (self.op >> 9) & 0b111;
+        let ry = self.op & 0b111;
+        if self.op & 0b1000 == 0 {
+            (Ea::D(ry as usize), Ea::D(rx as usize))
+        }
+        else {
+            let src = self.decode_ea(0b100000 | ry, size, EA_ANY);
+            let dst = self.decode_ea(0b100000 | rx, size, EA_ANY);
+            (src, dst)
+        }
     }
 
-    fn rot(&mut self) {
-        println!("rot");
+    //Adds src, dst and an extend bit of the given size, setting X, N, Z, V
+    //and C the way the whole ADD family does.
+    fn add_op(&mut self, src: u32, dst: u32, x: u32, size: u32) -> u32 {
+        let wide = src as u64 + dst as u64 + x as u64;
+        let res = wide as u32 & mask(size);
+        let msb = msb(size);
+        self.set_flag(CARRY | EXTEND, wide > mask(size) as u64);
+        //overflow is when both operands have the same sign but the result
+        //doesn't
+        self.set_flag(OVERFLOW, (src ^ res) & (dst ^ res) & msb != 0);
+        self.set_flag(NEGATIVE, res & msb != 0);
+        self.set_flag(ZERO, res == 0);
+        res
+    }
+
+    fn set_flag(&mut self, flag: u16, set: bool) {
+        if set {
+            self.sr |= flag;
+        }
+        else {
+            self.sr &= !flag;
+        }
     }
 
-    fn ls(&mut self) {
-        println!("ls");
+    fn extend(&self) -> u32 {
+        ((self.sr & EXTEND) >> 4) as u32
     }
 
-    fn eor(&mut self) {
+    //Decodes the whole shift/rotate line. The register forms shift Dy by an
+    //immediate count of 1-8 or by Dx mod 64, and have the kind of shift in
+    //bits 4-3. The memory forms always shift a word in memory by one bit,
+    //and have the kind in bits 10-9 instead, with bit 11 clear.
+    fn shift(&mut self) {
+        let left = self.op & 0x100 != 0;
+        if (self.op >> 6) & 0b11 == 0b11 {
+            if self.op & 0x800 != 0 {
+                self.illegal();
+                return;
+            }
+            let kind = (self.op >> 9) & 0b11;
+            let ea = self.decode_ea(self.op & 0x3f, 2, EA_MEMORY | EA_ALTERABLE);
+            let data = self.read_ea(ea, 2);
+            let res = self.shift_op(kind, left, data, 1, 2);
+            self.write_ea(ea, res, 2);
+        }
+        else {
+            let size = 1 << ((self.op >> 6) & 0b11);
+            let kind = (self.op >> 3) & 0b11;
+            let count = if self.op & 0b100000 != 0 {
+                self.d[((self.op >> 9) & 0b111) as usize] % 64
+            }
+            else {
+                quick_data(self.op)
+            };
+            let reg = (self.op & 0b111) as usize;
+            let res = self.shift_op(kind, left, self.d[reg] & mask(size), count, size);
+            self.d[reg] = by_byte(res, self.d[reg], size);
+        }
+    }
+
+    //Shifts data one bit at a time, since the flags depend on every bit that
+    //goes past. kind is 0 for ASd, 1 for LSd, 2 for ROXd and 3 for ROd.
+    //C is the last bit shifted out, X follows it for everything but ROd,
+    //and V is only ever set by ASL, when the sign bit changes at any point.
+    fn shift_op(&mut self, kind: u16, left: bool, mut data: u32, count: u32, size: u32) -> u32 {
+        let msb = msb(size);
+        let mut extend = self.sr & EXTEND != 0;
+        let mut carry = false;
+        let mut overflow = false;
+        for _ in 0..count {
+            let out = if left { data & msb != 0 } else { data & 1 != 0 };
+            let fill = match kind {
+                0 => !left && data & msb != 0, //ASR copies the sign bit down
+                2 => extend,
+                3 => out,
+                _ => false,
+            };
+            if left {
+                data = ((data << 1) & mask(size)) | fill as u32;
+                overflow |= kind == 0 && (data & msb != 0) != out;
+            }
+            else {
+                data = (data >> 1) | if fill { msb } else { 0 };
+            }
+            carry = out;
+            if kind != 3 {
+                extend = out;
+            }
+        }
+        //ROXd with a count of 0 still copies X into C
+        self.set_flag(CARRY, if kind == 2 { extend } else { carry });
+        self.set_flag(EXTEND, extend);
+        self.set_flag(OVERFLOW, overflow);
+        self.set_flag(NEGATIVE, data & msb != 0);
+        self.set_flag(ZERO, data == 0);
+        data
+    }
 
+    fn eor(&mut self) {
+        self.logic(|src, dst| src ^ dst);
     }
 
     fn cmp(&mut self) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ANY);
+        let data = self.read_ea(ea, size);
+        self.cmp_op(data, self.d[reg] & mask(size), size);
+    }
+
+    fn cmpa(&mut self) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = if self.op & 0x100 != 0 { 4 } else { 2 };
+        let ea = self.decode_ea(self.op & 0x3f, size, EA_ANY);
+        let src = sign_extend(self.read_ea(ea, size), size);
+        self.cmp_op(src, self.a[reg], 4);
+    }
+
+    fn cmpm(&mut self) {
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let src = self.decode_ea(0b011000 | (self.op & 0b111), size, EA_ANY);
+        let dst = self.decode_ea(0b011000 | ((self.op >> 9) & 0b111), size, EA_ANY);
+        let data = self.read_ea(src, size);
+        let data2 = self.read_ea(dst, size);
+        self.cmp_op(data, data2, size);
+    }
 
+    //A compare is a subtraction that throws away the result and leaves X
+    //alone.
+    fn cmp_op(&mut self, src: u32, dst: u32, size: u32) {
+        let x = self.sr & EXTEND;
+        self.sub_op(src, dst, 0, size);
+        self.sr = (self.sr & !EXTEND) | x;
     }
 
     fn sub(&mut self) {
+        let reg = ((self.op >> 9) & 0b111) as usize;
+        let size = 1 << ((self.op >> 6) & 0b11);
+        //the <ea> can only be a destination if it's in memory
+        let modes = if self.op & 0x100 == 0 { EA_ANY } else { EA_MEMORY | EA_ALTERABLE };
+        let ea = self.decode_ea(self.op & 0x3f, size, modes);
+        let data = self.read_ea(ea, size);
+        if self.op & 0x100 == 0 {
+            //Dn - <ea> -> Dn
+            let res = self.sub_op(data, self.d[reg] & mask(size), 0, size);
+            self.d[reg] = by_byte(res, self.d[reg], size);
+        }
+        else {
+            //<ea> - Dn -> <ea>
+            let res = self.sub_op(self.d[reg] & mask(size), data, 0, size);
+            self.write_ea(ea, res, size);
+        }
+    }
 
+    fn subx(&mut self) {
+        let size = 1 << ((self.op >> 6) & 0b11);
+        let (src, dst) = self.x_operands(size);
+        let data = self.read_ea(src, size);
+        let data2 = self.read_ea(dst, size);
+        let zero = self.sr & ZERO != 0;
+        let res = self.sub_op(data, data2, self.extend(), size);
+        if res == 0 {
+            self.set_flag(ZERO, zero);
+        }
+        self.write_ea(dst, res, size);
     }
 
-    fn or(&mut self){
+    //Subtracts src and an extend bit from dst, setting the flags the same
+    //way for the whole SUB family. C and X are set on a borrow.
+    fn sub_op(&mut self, src: u32, dst: u32, x: u32, size: u32) -> u32 {
+        let borrow = src as u64 + x as u64 > dst as u64;
+        let res = dst.wrapping_sub(src).wrapping_sub(x) & mask(size);
+        let msb = msb(size);
+        self.set_flag(CARRY | EXTEND, borrow);
+        //overflow is when the operands have different signs and the result
+        //has the sign of the source
+        self.set_flag(OVERFLOW, (src ^ dst) & (res ^ dst) & msb != 0);
+        self.set_flag(NEGATIVE, res & msb != 0);
+        self.set_flag(ZERO, res == 0);
+        res
+    }
 
+    fn or(&mut self){
+        self.logic(|src, dst| src | dst);
     }
 
     fn sbcd(&mut self){
+        let (src, dst) = self.x_operands(1);
+        let data = self.read_ea(src, 1);
+        let data2 = self.read_ea(dst, 1);
+        let res = self.bcd_sub(data, data2);
+        self.write_ea(dst, res, 1);
+    }
+
+    fn nbcd(&mut self) {
+        let ea = self.decode_ea(self.op & 0x3f, 1, EA_DATA | EA_ALTERABLE);
+        let data = self.read_ea(ea, 1);
+        let res = self.bcd_sub(data, 0);
+        self.write_ea(ea, res, 1);
+    }
+
+    //Packed BCD add with extend. The binary sum is decimal adjusted one
+    //digit at a time, the same way the real ALU does it, so invalid BCD
+    //inputs give the same results as silicon. N and V aren't documented,
+    //but the 68000 sets N from bit 7 of the result and V when the decimal
+    //adjust carries into bit 7.
+    fn bcd_add(&mut self, src: u32, dst: u32) -> u32 {
+        let low = (src & 0xf) + (dst & 0xf) + self.extend();
+        let mut res = if low > 9 { low + 6 } else { low };
+        res += (src & 0xf0) + (dst & 0xf0);
+        let carry = res > 0x99;
+        if carry {
+            res -= 0xa0;
+        }
+        self.bcd_flags(res & 0xff, carry, !low & res & 0x80 != 0)
+    }
 
+    //Packed BCD dst - src - X, adjusted the same way as bcd_add.
+    fn bcd_sub(&mut self, src: u32, dst: u32) -> u32 {
+        let low = (dst & 0xf).wrapping_sub(src & 0xf).wrapping_sub(self.extend());
+        let mut res = if low > 9 { low.wrapping_sub(6) } else { low };
+        res = res.wrapping_add(dst & 0xf0).wrapping_sub(src & 0xf0);
+        let carry = res > 0x99;
+        if carry {
+            res = res.wrapping_add(0xa0);
+        }
+        self.bcd_flags(res & 0xff, carry, !low & res & 0x80 != 0)
     }
 
-    fn reset(&mut self){
+    //Like ADDX and SUBX, Z is only ever cleared, so a string of digits can
+    //be checked for zero after the last one.
+    fn bcd_flags(&mut self, res: u32, carry: bool, overflow: bool) -> u32 {
+        self.set_flag(CARRY | EXTEND, carry);
+        self.set_flag(OVERFLOW, overflow);
+        self.set_flag(NEGATIVE, res & 0x80 != 0);
+        if res != 0 {
+            self.set_flag(ZERO, false);
+        }
+        res
+    }
 
+    //Only resets the devices on the reset line, the CPU itself carries on
+    //with the next instruction.
+    fn reset(&mut self){
+        if self.privileged() {
+            if let Some(ref mut devices) = self.reset_line {
+                devices();
+            }
+        }
     }
 
     fn next_l(&mut self) -> u32 {
         ((self.next_op() as u32) << 16) + (self.next_op() as u32)
     }
 
+    //Most ops keep their size in bits 7-6. 11 isn't a size, and for the ops
+    //that don't reuse it to mean something else it's an illegal instruction,
+    //which is taken here before anything past the opcode is fetched.
+    fn size_field(&mut self) -> Option<u32> {
+        match (self.op >> 6) & 0b11 {
+            0b11 => {
+                self.illegal();
+                None
+            }
+            size => Some(1 << size),
+        }
+    }
+
+    //Fetches an immediate operand of the given size. Byte immediates still
+    //take up a whole extension word, with the data in the low byte.
+    fn next_imm(&mut self, size: u32) -> u32 {
+        match size {
+            4 => self.next_l(),
+            2 => self.next_op() as u32,
+            _ => (self.next_op() & 0xff) as u32,
+        }
+    }
+
+    //Decodes a 6 bit effective address field (mode in bits 5-3, register in
+    //bits 2-0) for an operand of the given size. Any extension words are
+    //fetched here, and the (An)+ and -(An) modes update their register here,
+    //so the operand this returns can be read and then written back without
+    //repeating the side effects. modes is the categories of addressing mode
+    //the instruction can use, and anything else abandons it as an illegal
+    //instruction, as do address registers for byte operands. Apart from
+    //MOVEM, which steps (An)+ and -(An) itself for the whole register list,
+    //handlers leave the mode bits to this.
+    fn decode_ea(&mut self, field: u16, size: u32, modes: u8) -> Ea {
+        let reg = (field & 0b111) as usize;
+        let mode = (field >> 3) & 0b111;
+        if ea_modes(field) & modes != modes || (mode == 1 && size == 1) {
+            self.invalid_ea();
+        }
+        match mode {
+            0 => Ea::D(reg), //Dn
+            1 => Ea::A(reg), //An
+            2 => Ea::Mem(self.a[reg]), //(An)
+            3 => { //(An)+
+                let addr = self.a[reg];
+                self.a[reg] = addr.wrapping_add(step(reg, size));
+                Ea::Mem(addr)
+            }
+            4 => { //-(An)
+                self.a[reg] = self.a[reg].wrapping_sub(step(reg, size));
+                Ea::Mem(self.a[reg])
+            }
+            5 => { //(d16,An)
+                let disp = self.next_op() as i16 as u32;
+                Ea::Mem(self.a[reg].wrapping_add(disp))
+            }
+            6 => { //(d8,An,Xn)
+                let base = self.a[reg];
+                Ea::Mem(self.index(base))
+            }
+            _ => {
+                match reg {
+                    0 => Ea::Mem(self.next_op() as i16 as u32), //abs.W
+                    1 => Ea::Mem(self.next_l()), //abs.L
+                    2 => { //(d16,PC) - relative to the extension word
+                        let base = self.pc;
+                        let disp = self.next_op() as i16 as u32;
+                        Ea::Mem(base.wrapping_add(disp))
+                    }
+                    3 => { //(d8,PC,Xn)
+                        let base = self.pc;
+                        Ea::Mem(self.index(base))
+                    }
+                    4 => Ea::Imm(self.next_imm(size)), //#imm
+                    _ => {
+                        self.invalid_ea();
+                        Ea::Imm(0)
+                    }
+                }
+            }
+        }
+    }
+
+    //Adds a brief extension word to a base address: the low byte is a signed
+    //displacement, and bits 15-11 pick an index register and whether to use
+    //all of it or just its sign extended low word.
+    fn index(&mut self, base: u32) -> u32 {
+        let ext = self.next_op();
+        let reg = ((ext >> 12) & 0b111) as usize;
+        let mut xn = if ext & 0x8000 != 0 { self.a[reg] } else { self.d[reg] };
+        if ext & 0x800 == 0 {
+            xn = sign_extend(xn, 2);
+        }
+        base.wrapping_add(sign_extend(ext as u32, 1)).wrapping_add(xn)
+    }
+
+    fn read_ea(&mut self, ea: Ea, size: u32) -> u32 {
+        match ea {
+            Ea::D(reg) => self.d[reg] & mask(size),
+            Ea::A(reg) => self.a[reg] & mask(size),
+            Ea::Mem(addr) => self.read_mem(addr, size),
+            Ea::Imm(data) => data & mask(size),
+        }
+    }
+
+    //Data registers only have the low byte or word replaced, but address
+    //registers are always written whole, with words sign extended.
+    fn write_ea(&mut self, ea: Ea, data: u32, size: u32) {
+        match ea {
+            Ea::D(reg) => self.d[reg] = by_byte(data, self.d[reg], size),
+            Ea::A(reg) => self.a[reg] = sign_extend(data, size),
+            Ea::Mem(addr) => self.write_mem(addr, data, size),
+            Ea::Imm(_) => self.invalid_ea(),
+        }
+    }
+
+    //For an operand in an addressing mode the instruction can't use. That
+    //isn't known until it has been decoded, so like an address error the
+    //instruction is abandoned, and the illegal instruction exception is
+    //taken once run has undone it.
+    fn invalid_ea(&mut self) {
+        if self.fault.is_none() {
+            self.fault = Some(Fault::Illegal);
+        }
+    }
+
+    fn read_mem(&mut self, addr: u32, size: u32) -> u32 {
+        if !self.aligned(addr, size, true, false) {
+            return 0;
+        }
+        let addr = addr as usize;
+        match size {
+            1 => self.memory.read_b(addr) as u32,
+            2 => self.memory.read_w(addr) as u32,
+            _ => self.memory.read_l(addr),
+        }
+    }
+
+    fn write_mem(&mut self, addr: u32, data: u32, size: u32) {
+        if !self.aligned(addr, size, false, false) {
+            return;
+        }
+        self.memory.mem_write(addr as usize, data, size);
+    }
+
+    fn push(&mut self, data: u32, size: u32) {
+        self.a[7] = self.a[7].wrapping_sub(size);
+        let sp = self.a[7];
+        self.write_mem(sp, data, size);
+    }
+
+    fn pop(&mut self, size: u32) -> u32 {
+        let sp = self.a[7];
+        self.a[7] = sp.wrapping_add(size);
+        self.read_mem(sp, size)
+    }
+
+    //All exceptions end up here. Switches to supervisor mode with tracing
+    //off, pushes the PC and the old SR onto the supervisor stack, and jumps
+    //to the handler whose address is in the 256 entry vector table at the
+    //bottom of memory. Callers set the PC beforehand to whatever the
+    //exception is meant to stack: the next instruction for traps, or the
+    //faulting one for illegal instructions and privilege violations.
+    fn exception(&mut self, vector: u32) {
+        if self.fault.is_some() {
+            return; //the instruction is being abandoned anyway
+        }
+        let sr = self.sr;
+        self.set_sr((sr | SUPERVISOR) & !TRACE);
+        let pc = self.pc;
+        self.push(pc, 4);
+        self.push(sr as u32, 2);
+        if let Some(Fault::Address(_, ref mut status)) = self.fault {
+            *status |= 0b1000; //faulted while stacking the frame
+        }
+        self.pc = self.read_mem(vector * 4, 4);
+    }
+
+    //Words and longs have to be on even addresses. Anything else is an
+    //address error, which is recorded here and taken once the instruction
+    //has been abandoned. Until then the rest of its accesses are dropped,
+    //reads coming back as 0, and run undoes its register changes after. The
+    //status word stacked with it has R/W in bit 4, I/N in bit 3 (set by
+    //exception if it was stacking a frame rather than running an
+    //instruction), and the function code, which says whether it was a user
+    //or supervisor, program or data access, in bits 0-2.
+    fn aligned(&mut self, addr: u32, size: u32, read: bool, program: bool) -> bool {
+        if self.fault.is_some() {
+            return false;
+        }
+        if size == 1 || addr & 1 == 0 {
+            return true;
+        }
+        let mut status = if program { 0b10 } else { 0b01 };
+        if self.sr & SUPERVISOR != 0 {
+            status |= 0b100;
+        }
+        if read {
+            status |= 0b10000;
+        }
+        self.fault = Some(Fault::Address(addr, status));
+        false
+    }
+
+    //Address errors stack a bigger frame than other exceptions: below the
+    //usual PC and SR go the opcode, the address that was accessed and the
+    //status word. If stacking that faults too, the CPU gives up and halts.
+    fn address_error(&mut self, addr: u32, status: u16) {
+        let sr = self.sr;
+        self.set_sr((sr | SUPERVISOR) & !TRACE);
+        let pc = self.pc;
+        let op = self.op;
+        self.push(pc, 4);
+        self.push(sr as u32, 2);
+        self.push(op as u32, 2);
+        self.push(addr, 4);
+        self.push(status as u32, 2);
+        if self.fault.take().is_some() {
+            self.halted = true;
+            return;
+        }
+        self.pc = self.read_mem(ADDRESS_ERROR * 4, 4);
+    }
+
+    //Privileged instructions call this before fetching anything past the
+    //opcode. In user mode it takes a privilege violation, which stacks the
+    //address of the offending instruction, and returns false.
+    fn privileged(&mut self) -> bool {
+        if self.sr & SUPERVISOR != 0 {
+            return true;
+        }
+        self.abort(PRIVILEGE_VIOLATION);
+        false
+    }
+
+    //For exceptions that stop an instruction before it does anything. The
+    //PC stacked is the address of the instruction itself, and since it
+    //never executed it isn't traced either.
+    fn abort(&mut self, vector: u32) {
+        self.pc = self.pc.wrapping_sub(2);
+        self.tracing = false;
+        self.exception(vector);
+    }
+
+    //Everything that replaces the whole SR goes through here. Only the
+    //trace, supervisor, interrupt mask and condition code bits exist.
+    //A7 is whichever stack pointer goes with the current mode, so changing
+    //the S bit swaps the other one in.
+    fn set_sr(&mut self, sr: u16) {
+        let sr = sr & 0xa71f;
+        if (sr ^ self.sr) & SUPERVISOR != 0 {
+            if sr & SUPERVISOR != 0 {
+                self.usp = self.a[7];
+                self.a[7] = self.ssp;
+            }
+            else {
+                self.ssp = self.a[7];
+                self.a[7] = self.usp;
+            }
+        }
+        self.sr = sr;
+    }
+
+}
+
+//An operand location, as decoded by decode_ea. Every mode that ends up
+//pointing into memory is collapsed into Mem, since once the address is
+//known the instructions don't care how it was calculated.
+#[derive(Clone, Copy)]
+enum Ea {
+    D(usize),
+    A(usize),
+    Mem(u32),
+    Imm(u32),
+}
+
+//Why an instruction gave up partway through. Either way run puts the
+//registers back the way they were when it started before taking the
+//exception.
+#[derive(Clone, Copy)]
+enum Fault {
+    Address(u32, u16), //odd word or long access: the address and status word
+    Illegal, //an operand in an addressing mode the instruction can't use
+}
+
+//The categories of addressing mode, for saying which ones an instruction
+//can use. An operand has to be in all of the ones asked for.
+const EA_ANY: u8 = 0;
+const EA_DATA: u8 = 0b1; //everything but An
+const EA_MEMORY: u8 = 0b10; //everything but Dn and An
+const EA_CONTROL: u8 = 0b100; //memory, but not (An)+, -(An) or #imm
+const EA_ALTERABLE: u8 = 0b1000; //can be written, so not PC relative or #imm
+
+//Which of those categories an effective address field is in. The unused
+//mode 7 fields aren't in any, and decode_ea rejects them regardless.
+fn ea_modes(field: u16) -> u8 {
+    match ((field >> 3) & 0b111, field & 0b111) {
+        (0, _) => EA_DATA | EA_ALTERABLE,
+        (1, _) => EA_ALTERABLE,
+        (3, _) | (4, _) => EA_DATA | EA_MEMORY | EA_ALTERABLE,
+        (7, 2) | (7, 3) => EA_DATA | EA_MEMORY | EA_CONTROL,
+        (7, 4) => EA_DATA | EA_MEMORY,
+        (7, reg) if reg > 4 => 0,
+        _ => EA_DATA | EA_MEMORY | EA_CONTROL | EA_ALTERABLE,
+    }
+}
+
+//How far (An)+ and -(An) move the register. A7 is the stack pointer and has
+//to stay word aligned, so byte operations on it move it by 2.
+fn step(reg: usize, size: u32) -> u32 {
+    if reg == 7 && size == 1 {
+        2
+    }
+    else {
+        size
+    }
+}
+
+//ADDQ and SUBQ encode 1-8 in three bits, with 0 standing for 8.
+fn quick_data(op: u16) -> u32 {
+    match (op >> 9) & 0b111 {
+        0 => 8,
+        data => data as u32,
+    }
+}
+
+fn msb(size: u32) -> u32 {
+    1 << (size * 8 - 1)
+}
+
+fn mask(size: u32) -> u32 {
+    match size {
+        1 => 0xff,
+        2 => 0xffff,
+        _ => 0xffffffff,
+    }
+}
+
+fn sign_extend(data: u32, size: u32) -> u32 {
+    match size {
+        1 => data as u8 as i8 as u32,
+        2 => data as u16 as i16 as u32,
+        _ => data,
+    }
 }
 
 //SR: CVZNX
+const CARRY: u16 = 0b1;
+const OVERFLOW: u16 = 0b10;
+const ZERO: u16 = 0b100;
+const NEGATIVE: u16 = 0b1000;
+const EXTEND: u16 = 0b10000;
+const SUPERVISOR: u16 = 0x2000;
+const TRACE: u16 = 0x8000;
+
+//exception vector numbers, the handler for each is at 4 * its number. 2 is
+//the bus error, which isn't modelled: all 16mb is backed by memory, so
+//there's never a missing device to time out on.
+const ADDRESS_ERROR: u32 = 3;
+const ILLEGAL_INSTRUCTION: u32 = 4;
+const ZERO_DIVIDE: u32 = 5;
+const CHK: u32 = 6;
+const TRAPV: u32 = 7;
+const PRIVILEGE_VIOLATION: u32 = 8;
+const TRACE_EXCEPTION: u32 = 9;
+const LINE_A: u32 = 10;
+const LINE_F: u32 = 11;
+const AUTOVECTOR_BASE: u32 = 24; //levels 1-7 are 25-31
+const TRAP_BASE: u32 = 32; //TRAP #0-15 are 32-47
 
 fn condition(code: u16, status: u16) -> bool{
     match code {
@@ -1054,79 +1767,1156 @@ fn condition(code: u16, status: u16) -> bool{
 
 fn by_byte(from: u32, to: u32, mode: u32) -> u32 {
     match mode {
-        4 => return from, //long
+        4 => from, //long
         2 => {
             //word
             let temp = from & 0b00000000000000001111111111111111;
             let temp2 = to & 0b11111111111111110000000000000000;
-            return temp + temp2;
+            temp + temp2
         }
         1 => {
             //byte
             let temp = from & 0b00000000000000000000000011111111;
             let temp2 = to & 0b11111111111111111111111100000000;
-            return temp + temp2;
+            temp + temp2
         }
         _ => 0,
     }
 }
 
-
+//Dumps the registers to stdout. The core itself never prints anything, so
+//it's up to the host to call this if it wants to watch the program run.
 pub fn debug_print(test: &M68k) {
-    let mut i = 0;
-    for x in (*test).a.iter() {
+    println!("{}", test.pc);
+    for (i, x) in test.a.iter().enumerate() {
         println!("A{}: {:X}", i, x);
-        i += 1;
     }
-    i = 0;
-    for x in (*test).d.iter() {
+    for (i, x) in test.d.iter().enumerate() {
         println!("D{}: {:X}", i, x);
-        i += 1;
     }
     println!("{:#018b}", test.sr);
+    //a[7] is already one of these, print the other
+    if test.sr & SUPERVISOR != 0 {
+        println!("supervisor mode, USP: {:X}", test.usp);
+    }
+    else {
+        println!("user mode, SSP: {:X}", test.ssp);
+    }
 }
 
 pub struct Mem {
     m: Vec<u8>,
 }
 
+//The 68000 only has 24 address lines, so anything above that wraps around.
+const ADDR_MASK: usize = 0xffffff;
+
+impl Default for Mem {
+    fn default() -> Mem {
+        Mem::new()
+    }
+}
+
 impl Mem {
     pub fn new() -> Mem {
         Mem {
-            m: vec![0; 0xffffff],
+            m: vec![0; ADDR_MASK + 1],
         }
     }
 
+    //The 68000 is big endian, so the most significant byte of a word or
+    //long always goes at the lowest address.
     pub fn mem_write(&mut self, addr: usize, data: u32, mode: u32) {
-        match mode {
-            4 => {
-                self.m[addr + 3] = (data >> 24) as u8;
-                self.m[addr + 2] = (data >> 16) as u8;
-                self.m[addr + 1] = (data >> 8) as u8;
-                self.m[addr] = data as u8;
-            }
-            2 => {
-                self.m[addr + 1] = (data >> 8) as u8;
-                self.m[addr] = data as u8;
-            }
-            1 => {
-                self.m[addr] = data as u8;
-            }
-            _ => {}
+        for i in 0..mode as usize {
+            let shift = 8 * (mode as usize - 1 - i);
+            self.m[(addr + i) & ADDR_MASK] = (data >> shift) as u8;
         }
     }
 
     pub fn read_b(&mut self, addr: usize) -> u8 {
         //The CPU leaves data in an array of u8s, and whatever needs to see
         //those values can request them here. This is a stopgap solution.
-        self.m[addr]
+        self.m[addr & ADDR_MASK]
     }
 
     pub fn read_w(&mut self, addr: usize) -> u16 {
-        ((self.m[addr + 1] as u16) << 8) + (self.m[addr] as u16)
+        ((self.read_b(addr) as u16) << 8) + (self.read_b(addr + 1) as u16)
     }
 
     pub fn read_l(&mut self, addr: usize) -> u32 {
-        ((self.read_w(addr + 2) as u32) << 16) + (self.read_w(addr as usize) as u32)
+        ((self.read_w(addr) as u32) << 16) + (self.read_w(addr + 2) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A CPU in user mode with the given opcode words at 0x1000, the PC
+    //pointing at them, and both stacks well out of the way.
+    fn cpu(code: &[u16]) -> M68k {
+        let mut cpu = M68k::init();
+        for (i, word) in code.iter().enumerate() {
+            cpu.memory.mem_write(0x1000 + 2 * i, *word as u32, 2);
+        }
+        cpu.prog = vec![0; 0x1000 + 2 * code.len()];
+        cpu.raw = true;
+        cpu.pc = 0x1000;
+        cpu.sr = 0;
+        cpu.a[7] = 0x8000;
+        cpu.ssp = 0x9000;
+        cpu
+    }
+
+    fn flags(cpu: &M68k) -> u16 {
+        cpu.sr & 0x1f
+    }
+
+    //Runs the instruction at the start of code and checks that it took the
+    //exception for vector, stacking its own address.
+    fn assert_exception(code: &[u16], vector: u32) {
+        let mut cpu = cpu(code);
+        cpu.memory.mem_write(vector as usize * 4, 0x3000, 4);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000, "{:04x}", code[0]);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1000, "{:04x}", code[0]);
+    }
+
+    #[test]
+    fn postincrement_and_predecrement() {
+        //move.b (a7)+,d0 ; move.b d1,-(a7) ; move.b (a0)+,d2 ; move.w -(a0),d3
+        let mut cpu = cpu(&[0x101f, 0x1f01, 0x1418, 0x3620]);
+        cpu.memory.mem_write(0x8000, 0xab, 1);
+        cpu.d[1] = 0xcd;
+        cpu.a[0] = 0x2000;
+        cpu.memory.mem_write(0x2000, 0x1234, 2);
+        cpu.run();
+        assert_eq!(cpu.d[0], 0xab);
+        assert_eq!(cpu.a[7], 0x8002); //A7 stays word aligned
+        cpu.run();
+        assert_eq!(cpu.a[7], 0x8000);
+        assert_eq!(cpu.memory.read_b(0x8000), 0xcd);
+        cpu.run();
+        assert_eq!(cpu.d[2], 0x12);
+        assert_eq!(cpu.a[0], 0x2001);
+        cpu.a[0] = 0x2002;
+        cpu.run();
+        assert_eq!(cpu.d[3], 0x1234);
+        assert_eq!(cpu.a[0], 0x2000);
+    }
+
+    #[test]
+    fn displacement_and_index() {
+        //move.w -2(a0),d3 ; move.w 2(a0,d1.w),d4 ; move.w 2(a0,d1.l),d5
+        let mut cpu = cpu(&[0x3628, 0xfffe, 0x3830, 0x1002, 0x3a30, 0x1802]);
+        cpu.a[0] = 0x2002;
+        cpu.d[1] = 0x0001_fffc; //-4 as a word
+        cpu.memory.mem_write(0x2000, 0x1111, 2);
+        cpu.memory.mem_write(0x22000, 0x2222, 2);
+        cpu.run();
+        assert_eq!(cpu.d[3], 0x1111);
+        cpu.run();
+        assert_eq!(cpu.d[4], 0x1111);
+        cpu.run();
+        assert_eq!(cpu.d[5], 0x2222);
+    }
+
+    #[test]
+    fn pc_relative() {
+        //move.w 2(pc),d5 ; move.w 2(pc,d1.w),d6, each followed by its data.
+        //The base is the address of the extension word, not the opcode.
+        let mut cpu = cpu(&[0x3a3a, 0x0002, 0xbeef, 0x3c3b, 0x1002, 0xcafe]);
+        cpu.run();
+        assert_eq!(cpu.d[5], 0xbeef);
+        cpu.pc = 0x1006;
+        cpu.run();
+        assert_eq!(cpu.d[6], 0xcafe);
+    }
+
+    #[test]
+    fn absolute_and_immediate() {
+        //lea $8000.w,a1 ; lea $12345678,a2 ; move.b #$12,d7
+        let mut cpu = cpu(&[0x43f8, 0x8000, 0x45f9, 0x1234, 0x5678, 0x1e3c, 0x0012]);
+        cpu.run();
+        assert_eq!(cpu.a[1], 0xffff_8000);
+        cpu.run();
+        assert_eq!(cpu.a[2], 0x1234_5678);
+        cpu.run();
+        assert_eq!(cpu.d[7], 0x12);
+        assert_eq!(cpu.pc, 0x100e);
+    }
+
+    #[test]
+    fn modes_an_instruction_cant_use() {
+        //move.w d0,16(pc) ; clr.w 16(pc) ; addq.b #1,a0 ; move.b a0,d0 ;
+        //movea.b d0,a0 ; lea (a0)+,a1 ; tst.w with mode 7 register 5
+        let codes: [&[u16]; 7] = [&[0x35c0, 0x0010], &[0x427a, 0x0010], &[0x5208],
+            &[0x1008], &[0x1040], &[0x43d8], &[0x4a7d]];
+        for code in codes.iter() {
+            let mut cpu = cpu(code);
+            cpu.memory.mem_write(ILLEGAL_INSTRUCTION as usize * 4, 0x3000, 4);
+            cpu.memory.mem_write(0x1012, 0x1234, 2);
+            cpu.d[0] = 0xbeef;
+            cpu.a[0] = 0x2000;
+            cpu.run();
+            assert_eq!(cpu.pc, 0x3000, "{:04x}", code[0]);
+            assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1000);
+            assert_eq!(cpu.memory.read_w(0x1012), 0x1234);
+            assert_eq!((cpu.d[0], cpu.a[0]), (0xbeef, 0x2000));
+        }
+    }
+
+    #[test]
+    fn add_flags() {
+        //add.b d1,d0 ; add.b d1,d0 ; addx.l d3,d2
+        let mut cpu = cpu(&[0xd001, 0xd001, 0xd583]);
+        cpu.d[0] = 0x7f;
+        cpu.d[1] = 0x01;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(flags(&cpu), NEGATIVE | OVERFLOW);
+        cpu.d[0] = 0xff;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0);
+        assert_eq!(flags(&cpu), EXTEND | ZERO | CARRY);
+        //ADDX adds in X, and only ever clears Z
+        cpu.d[2] = 0xffff_ffff;
+        cpu.sr = EXTEND | ZERO;
+        cpu.run();
+        assert_eq!(cpu.d[2], 0);
+        assert_eq!(flags(&cpu), EXTEND | ZERO | CARRY);
+    }
+
+    #[test]
+    fn add_forms() {
+        //adda.w d4,a0 ; addq.w #1,a1 ; addq.l #8,d5 ; addi.w #$7fff,d6 ; add.w d0,(a2)
+        let mut cpu = cpu(&[0xd0c4, 0x5249, 0x5085, 0x0646, 0x7fff, 0xd152]);
+        cpu.d[4] = 0xfffe;
+        cpu.a[0] = 0x10;
+        cpu.a[1] = 0xffff;
+        cpu.d[6] = 1;
+        cpu.d[0] = 0x0102;
+        cpu.a[2] = 0x2000;
+        cpu.memory.mem_write(0x2000, 0x0304, 2);
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.a[0], 0xe); //sign extended, flags untouched
+        cpu.run();
+        assert_eq!(cpu.a[1], 0x10000); //the whole register, not just the word
+        assert_eq!(flags(&cpu), ZERO);
+        cpu.run();
+        assert_eq!(cpu.d[5], 8);
+        cpu.run();
+        assert_eq!(cpu.d[6], 0x8000);
+        assert_eq!(flags(&cpu), NEGATIVE | OVERFLOW);
+        cpu.run();
+        assert_eq!(cpu.memory.read_w(0x2000), 0x0406);
+    }
+
+    #[test]
+    fn addi_size_11_is_illegal() {
+        assert_exception(&[0x06c0, 0, 0], ILLEGAL_INSTRUCTION);
+    }
+
+    #[test]
+    fn sub_flags() {
+        //sub.w d1,d0 ; sub.b d1,d0 ; subx.b d3,d2
+        let mut cpu = cpu(&[0x9041, 0x9001, 0x9503]);
+        cpu.d[0] = 0x8000;
+        cpu.d[1] = 0x01;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x7fff);
+        assert_eq!(flags(&cpu), OVERFLOW);
+        cpu.d[0] = 0;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0xff);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+        cpu.d[2] = 0x01;
+        cpu.sr = EXTEND | ZERO;
+        cpu.run();
+        assert_eq!(cpu.d[2], 0);
+        assert_eq!(flags(&cpu), ZERO);
+    }
+
+    #[test]
+    fn neg_and_negx() {
+        //neg.l d4 ; negx.w d5 ; negx.w d5
+        let mut cpu = cpu(&[0x4484, 0x4045, 0x4045]);
+        cpu.d[4] = 5;
+        cpu.run();
+        assert_eq!(cpu.d[4], 0xffff_fffb);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+        //0 - 0 - X
+        cpu.d[5] = 0;
+        cpu.run();
+        assert_eq!(cpu.d[5], 0xffff);
+        cpu.d[5] = 0;
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.d[5], 0);
+        assert_eq!(flags(&cpu), ZERO);
+    }
+
+    #[test]
+    fn sub_forms() {
+        //suba.w d1,a0 ; subq.w #1,a1 ; subi.b #1,(a2)
+        let mut cpu = cpu(&[0x90c1, 0x5349, 0x0412, 0x0001]);
+        cpu.d[1] = 0xffff;
+        cpu.a[0] = 0x10;
+        cpu.a[1] = 0x10000;
+        cpu.a[2] = 0x2000;
+        cpu.run();
+        assert_eq!(cpu.a[0], 0x11);
+        cpu.run();
+        assert_eq!(cpu.a[1], 0xffff);
+        assert_eq!(flags(&cpu), 0);
+        cpu.run();
+        assert_eq!(cpu.memory.read_b(0x2000), 0xff);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+    }
+
+    #[test]
+    fn subi_size_11_is_illegal() {
+        assert_exception(&[0x04c0, 0, 0], ILLEGAL_INSTRUCTION);
+    }
+
+    #[test]
+    fn compares() {
+        //cmp.b d1,d0 ; cmpa.w d2,a0 ; cmpi.l #5,d3 ; cmpm.w (a1)+,(a2)+
+        let mut cpu = cpu(&[0xb001, 0xb0c2, 0x0c83, 0, 5, 0xb549]);
+        cpu.d[0] = 0x10;
+        cpu.d[1] = 0x20;
+        cpu.sr = EXTEND;
+        cpu.d[2] = 0xffff;
+        cpu.a[0] = 0xffff_ffff;
+        cpu.d[3] = 5;
+        cpu.a[1] = 0x2000;
+        cpu.a[2] = 0x2010;
+        cpu.memory.mem_write(0x2000, 0x1234, 2);
+        cpu.memory.mem_write(0x2010, 0x1234, 2);
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x10); //nothing is written back
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+        //the word is sign extended to a long, and X is never touched
+        cpu.run();
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+        cpu.run();
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+        cpu.run();
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+        assert_eq!((cpu.a[1], cpu.a[2]), (0x2002, 0x2012));
+    }
+
+    #[test]
+    fn cmpi_size_11_is_illegal() {
+        assert_exception(&[0x0cc0, 0, 0], ILLEGAL_INSTRUCTION);
+    }
+
+    #[test]
+    fn asl_overflow() {
+        //asl.b #1,d0 ; asl.b #1,d0 ; asl.b #2,d0 ; lsl.b #1,d0
+        let mut cpu = cpu(&[0xe300, 0xe300, 0xe500, 0xe308]);
+        cpu.d[0] = 0x40;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(flags(&cpu), NEGATIVE | OVERFLOW);
+        //the sign bit doesn't change, so no overflow
+        cpu.d[0] = 0xc0;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+        //it does change partway through, even though it ends up the same
+        cpu.d[0] = 0xa0;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x80);
+        assert_eq!(flags(&cpu), NEGATIVE | OVERFLOW);
+        //LSL never sets V
+        cpu.d[0] = 0x40;
+        cpu.run();
+        assert_eq!(flags(&cpu), NEGATIVE);
+    }
+
+    #[test]
+    fn shifts_and_rotates() {
+        //asr.w #2,d1 ; lsr.l d2,d3 ; rol.b #1,d4 ; roxr.w #1,d5 ; ror.l #8,d6
+        let mut cpu = cpu(&[0xe441, 0xe4ab, 0xe31c, 0xe255, 0xe09e]);
+        cpu.d[1] = 0x8003;
+        cpu.d[2] = 64 + 4; //the count is taken mod 64
+        cpu.d[3] = 0x1f;
+        cpu.d[4] = 0x81;
+        cpu.d[6] = 0x1234_5678;
+        cpu.run();
+        assert_eq!(cpu.d[1], 0xe000);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE | CARRY);
+        cpu.run();
+        assert_eq!(cpu.d[3], 1);
+        assert_eq!(flags(&cpu), EXTEND | CARRY);
+        //ROd doesn't touch X
+        cpu.sr = 0;
+        cpu.run();
+        assert_eq!(cpu.d[4], 0x03);
+        assert_eq!(flags(&cpu), CARRY);
+        cpu.sr = EXTEND;
+        cpu.run();
+        assert_eq!(cpu.d[5], 0x8000);
+        assert_eq!(flags(&cpu), NEGATIVE);
+        cpu.run();
+        assert_eq!(cpu.d[6], 0x7812_3456);
+    }
+
+    #[test]
+    fn memory_shifts() {
+        //lsl.w (a0) ; asl.w with a data register ; bit 11 set
+        let mut cpu = cpu(&[0xe3d0]);
+        cpu.a[0] = 0x2000;
+        cpu.memory.mem_write(0x2000, 0x8001, 2);
+        cpu.run();
+        assert_eq!(cpu.memory.read_w(0x2000), 0x0002);
+        assert_eq!(flags(&cpu), EXTEND | CARRY);
+        assert_exception(&[0xe1c0], ILLEGAL_INSTRUCTION);
+        assert_exception(&[0xe8d0], ILLEGAL_INSTRUCTION);
+    }
+
+    #[test]
+    fn divu() {
+        //divu.w d1,d0 three times
+        let mut cpu = cpu(&[0x80c1, 0x80c1, 0x80c1]);
+        cpu.memory.mem_write(ZERO_DIVIDE as usize * 4, 0x3000, 4);
+        cpu.d[0] = 100;
+        cpu.d[1] = 7;
+        cpu.run();
+        assert_eq!(cpu.d[0], (2 << 16) | 14);
+        //the quotient doesn't fit in a word, so nothing is written
+        cpu.d[0] = 0x10000;
+        cpu.d[1] = 1;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x10000);
+        assert_eq!(flags(&cpu) & (OVERFLOW | CARRY), OVERFLOW);
+        cpu.d[1] = 0;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.d[0], 0x10000);
+    }
+
+    #[test]
+    fn multiply_and_divs() {
+        //mulu.w d1,d0 ; muls.w d3,d2 ; divs.w d5,d4
+        let mut cpu = cpu(&[0xc0c1, 0xc5c3, 0x89c5]);
+        cpu.d[0] = 0xffff_ffff;
+        cpu.d[1] = 0xffff;
+        cpu.d[2] = 0xfffe;
+        cpu.d[3] = 3;
+        cpu.d[4] = (-100i32) as u32;
+        cpu.d[5] = 7;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0xfffe_0001);
+        cpu.run();
+        assert_eq!(cpu.d[2], (-6i32) as u32);
+        assert_eq!(flags(&cpu), NEGATIVE);
+        //the remainder takes the sign of the dividend
+        cpu.run();
+        assert_eq!(cpu.d[4], (((-2i32) as u32) << 16) | 0xfff2);
+        assert_eq!(flags(&cpu), NEGATIVE);
+    }
+
+    #[test]
+    fn bcd() {
+        //abcd d1,d0 ; abcd d1,d0 ; sbcd d1,d0 ; sbcd d1,d0
+        let mut cpu = cpu(&[0xc101, 0xc101, 0x8101, 0x8101]);
+        cpu.d[0] = 0x19;
+        cpu.d[1] = 0x28;
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x47);
+        assert_eq!(flags(&cpu) & (EXTEND | ZERO | CARRY), 0);
+        cpu.d[0] = 0x99;
+        cpu.d[1] = 0x01;
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x00);
+        assert_eq!(flags(&cpu) & (EXTEND | ZERO | CARRY), EXTEND | ZERO | CARRY);
+        cpu.d[0] = 0x10;
+        cpu.sr = 0;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x09);
+        assert_eq!(flags(&cpu) & (EXTEND | CARRY), 0);
+        cpu.d[0] = 0x00;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x99);
+        assert_eq!(flags(&cpu) & (EXTEND | CARRY), EXTEND | CARRY);
+    }
+
+    #[test]
+    fn bcd_memory_and_nbcd() {
+        //abcd -(a1),-(a0) ; nbcd d4
+        let mut cpu = cpu(&[0xc109, 0x4804]);
+        cpu.a[0] = 0x2002;
+        cpu.a[1] = 0x2012;
+        cpu.memory.mem_write(0x2001, 0x99, 1);
+        cpu.memory.mem_write(0x2011, 0x01, 1);
+        cpu.d[4] = 0x01;
+        cpu.run();
+        assert_eq!(cpu.memory.read_b(0x2001), 0x00);
+        assert_eq!((cpu.a[0], cpu.a[1]), (0x2001, 0x2011));
+        assert_eq!(flags(&cpu) & (EXTEND | CARRY), EXTEND | CARRY);
+        //0 - 1 - X
+        cpu.run();
+        assert_eq!(cpu.d[4], 0x98);
+        assert_eq!(flags(&cpu) & (EXTEND | CARRY), EXTEND | CARRY);
+    }
+
+    #[test]
+    fn movem() {
+        //movem.l d0-d1/a0,-(a7) ; movem.w (a7)+,d2-d3 ; movem.l d0/a0,4(a1) ;
+        //movem.l 4(a1),d4/a2
+        let mut cpu = cpu(&[0x48e7, 0xc080, 0x4c9f, 0x000c, 0x48e9, 0x0101, 0x0004,
+            0x4ce9, 0x0410, 0x0004]);
+        cpu.d[0] = 0x1111_2222;
+        cpu.d[1] = 0x3333_8444;
+        cpu.a[0] = 0x5555_6666;
+        cpu.a[1] = 0x2000;
+        cpu.run();
+        assert_eq!(cpu.a[7], 0x8000 - 12);
+        assert_eq!(cpu.memory.read_l(0x8000 - 12), 0x1111_2222);
+        assert_eq!(cpu.memory.read_l(0x8000 - 8), 0x3333_8444);
+        assert_eq!(cpu.memory.read_l(0x8000 - 4), 0x5555_6666);
+        //words are sign extended, even into data registers
+        cpu.run();
+        assert_eq!(cpu.d[2], 0x1111);
+        assert_eq!(cpu.d[3], 0x2222);
+        assert_eq!(cpu.a[7], 0x8000 - 8);
+        cpu.run();
+        assert_eq!(cpu.memory.read_l(0x2004), 0x1111_2222);
+        assert_eq!(cpu.memory.read_l(0x2008), 0x5555_6666);
+        assert_eq!(cpu.a[1], 0x2000);
+        cpu.run();
+        assert_eq!(cpu.d[4], 0x1111_2222);
+        assert_eq!(cpu.a[2], 0x5555_6666);
+    }
+
+    #[test]
+    fn movem_modes_it_cant_use() {
+        //storing to (a0)+, #imm or 2(pc), loading from -(a0) or d0
+        for &op in &[0x4898, 0x48bc, 0x48ba, 0x4ca0, 0x4c80] {
+            let mut cpu = cpu(&[op, 0x0001, 0x0002]);
+            cpu.memory.mem_write(ILLEGAL_INSTRUCTION as usize * 4, 0x3000, 4);
+            cpu.d[0] = 0x1234;
+            cpu.a[0] = 0x2002;
+            cpu.memory.mem_write(0x2000, 0xaaaa_aaaa, 4);
+            cpu.run();
+            assert_eq!(cpu.pc, 0x3000, "{:04x}", op);
+            assert_eq!((cpu.d[0], cpu.a[0]), (0x1234, 0x2002));
+            assert_eq!(cpu.memory.read_l(0x2000), 0xaaaa_aaaa);
+        }
+    }
+
+    #[test]
+    fn opcode_zero_is_ori() {
+        //ori.b #5,d0 ; moveq #1,d1
+        let mut cpu = cpu(&[0x0000, 0x0005, 0x7201]);
+        cpu.run();
+        cpu.run();
+        assert_eq!(cpu.d[0], 5);
+        assert_eq!(cpu.d[1], 1);
+    }
+
+    #[test]
+    fn logic_ops() {
+        //and.w d1,d0 ; or.b d0,(a0) ; eor.l d2,d3 ; not.w d4 ; andi.b #$f0,d5
+        let mut cpu = cpu(&[0xc041, 0x8110, 0xb583, 0x4644, 0x0205, 0x00f0]);
+        cpu.d[0] = 0xffff_8f0f;
+        cpu.d[1] = 0x0000_f0ff;
+        cpu.a[0] = 0x2000;
+        cpu.memory.mem_write(0x2000, 0x30, 1);
+        cpu.d[2] = 0xffff_ffff;
+        cpu.d[3] = 0xffff_ffff;
+        cpu.d[4] = 0x1234_ffff;
+        cpu.d[5] = 0x0f;
+        cpu.sr = CARRY | OVERFLOW | EXTEND;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0xffff_800f);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE); //V and C cleared, X kept
+        cpu.run();
+        assert_eq!(cpu.memory.read_b(0x2000), 0x3f);
+        cpu.run();
+        assert_eq!(cpu.d[3], 0);
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+        cpu.run();
+        assert_eq!(cpu.d[4], 0x1234_0000);
+        cpu.run();
+        assert_eq!(cpu.d[5], 0);
+    }
+
+    #[test]
+    fn logic_to_ccr_and_sr() {
+        //ori.b #$1f,ccr ; andi.b #$fe,ccr ; eori.w #$2000,sr
+        let mut cpu = cpu(&[0x003c, 0x001f, 0x023c, 0x00fe, 0x0a7c, 0x2000]);
+        cpu.sr = SUPERVISOR;
+        cpu.run();
+        assert_eq!(cpu.sr, SUPERVISOR | 0x1f);
+        cpu.run();
+        assert_eq!(cpu.sr, SUPERVISOR | 0x1e);
+        cpu.run();
+        assert_eq!(cpu.sr, 0x1e); //now in user mode
+        //the SR forms are privileged
+        assert_exception(&[0x007c, 0x0700], PRIVILEGE_VIOLATION);
+    }
+
+    #[test]
+    fn logic_immediate_sizes_that_dont_exist() {
+        for &op in &[0x00c0, 0x02c0, 0x0ac0, 0x00bc, 0x02bc, 0x0abc] {
+            assert_exception(&[op, 0, 0], ILLEGAL_INSTRUCTION);
+        }
+    }
+
+    #[test]
+    fn bit_ops() {
+        //btst d1,d0 ; bchg d1,(a0) ; bclr #3,d2 ; bset d1,d3 ; btst d4,#$10
+        let mut cpu = cpu(&[0x0300, 0x0350, 0x0882, 0x0003, 0x03c3, 0x093c, 0x0010]);
+        cpu.d[0] = 0x8000_0000;
+        cpu.d[1] = 31 + 32; //mod 32 for registers
+        cpu.a[0] = 0x2000;
+        cpu.d[2] = 0xff;
+        cpu.d[4] = 4 + 8; //mod 8 for anything else
+        cpu.run();
+        assert_eq!(flags(&cpu), 0);
+        cpu.run();
+        assert_eq!(cpu.memory.read_b(0x2000), 0x80); //63 mod 8 is bit 7
+        assert_eq!(flags(&cpu), ZERO);
+        cpu.run();
+        assert_eq!(cpu.d[2], 0xf7);
+        assert_eq!(flags(&cpu), 0);
+        cpu.run();
+        assert_eq!(cpu.d[3], 0x8000_0000);
+        assert_eq!(flags(&cpu), ZERO);
+        cpu.run();
+        assert_eq!(flags(&cpu), 0);
+        //the static form can't test an immediate, and nothing can change one
+        assert_exception(&[0x083c, 0x0001, 0x00ff], ILLEGAL_INSTRUCTION);
+        assert_exception(&[0x097c, 0x00ff], ILLEGAL_INSTRUCTION);
+    }
+
+    #[test]
+    fn dbcc() {
+        //loop: dbf d0,loop ; dbeq d1,loop
+        let mut cpu = cpu(&[0x51c8, 0xfffe, 0x57c9, 0xfffa]);
+        cpu.d[0] = 0xaaaa_0002;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.d[0], 0xaaaa_0001);
+        cpu.run();
+        cpu.run();
+        //only the low word counts, and it stops once it passes 0
+        assert_eq!(cpu.pc, 0x1004);
+        assert_eq!(cpu.d[0], 0xaaaa_ffff);
+        //a true condition falls through without touching the counter
+        cpu.d[1] = 5;
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1008);
+        assert_eq!(cpu.d[1], 5);
+    }
+
+    #[test]
+    fn branches() {
+        //bra.s +4 ; (skipped) ; bne.w +$100 ; beq.s -8 ; bsr.w +$10
+        let mut cpu = cpu(&[0x6004, 0x4e71, 0x4e71, 0x6600, 0x0100, 0x67f8, 0x6100, 0x0010]);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1006);
+        //the word displacement is relative to the extension word
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1108);
+        cpu.pc = 0x1006;
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x100a); //not taken, and past the extension word
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1004);
+        //BSR pushes the address after the extension word
+        cpu.pc = 0x100c;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x101e);
+        assert_eq!(cpu.a[7], 0x8000 - 4);
+        assert_eq!(cpu.memory.read_l(0x8000 - 4), 0x1010);
+    }
+
+    #[test]
+    fn lea_pea_link_unlk() {
+        //lea 4(a0,d0.w),a1 ; pea 8(a0) ; link a6,#-8 ; unlk a6
+        let mut cpu = cpu(&[0x43f0, 0x0004, 0x4868, 0x0008, 0x4e56, 0xfff8, 0x4e5e]);
+        cpu.a[0] = 0x2000;
+        cpu.d[0] = 0xffff_fffe;
+        cpu.a[6] = 0x1234_5678;
+        cpu.run();
+        assert_eq!(cpu.a[1], 0x2002);
+        cpu.run();
+        assert_eq!(cpu.a[7], 0x8000 - 4);
+        assert_eq!(cpu.memory.read_l(0x8000 - 4), 0x2008);
+        //the old frame pointer is pushed and A6 points at it
+        cpu.run();
+        assert_eq!(cpu.a[6], 0x8000 - 8);
+        assert_eq!(cpu.memory.read_l(0x8000 - 8), 0x1234_5678);
+        assert_eq!(cpu.a[7], 0x8000 - 16);
+        cpu.run();
+        assert_eq!(cpu.a[6], 0x1234_5678);
+        assert_eq!(cpu.a[7], 0x8000 - 4);
+    }
+
+    #[test]
+    fn single_operand_ops() {
+        //clr.b d0 ; tst.w d1 ; ext.w d2 ; ext.l d2 ; swap d3 ; exg d4,a4 ; tas (a0)
+        let mut cpu = cpu(&[0x4200, 0x4a41, 0x4882, 0x48c2, 0x4843, 0xc98c, 0x4ad0]);
+        cpu.d[0] = 0x1234_5678;
+        cpu.d[1] = 0xffff_8000;
+        cpu.d[2] = 0x1234_0080;
+        cpu.d[3] = 0x1234_5678;
+        cpu.d[4] = 4;
+        cpu.a[4] = 0xa4;
+        cpu.a[0] = 0x2000;
+        cpu.memory.mem_write(0x2000, 0x00, 1);
+        cpu.sr = CARRY | OVERFLOW | EXTEND;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0x1234_5600);
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+        cpu.run();
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE);
+        cpu.run();
+        assert_eq!(cpu.d[2], 0x1234_ff80);
+        cpu.run();
+        assert_eq!(cpu.d[2], 0xffff_ff80);
+        cpu.run();
+        assert_eq!(cpu.d[3], 0x5678_1234);
+        assert_eq!(flags(&cpu), EXTEND);
+        cpu.run();
+        assert_eq!((cpu.d[4], cpu.a[4]), (0xa4, 4));
+        //flags come from the byte before its top bit is set
+        cpu.run();
+        assert_eq!(cpu.memory.read_b(0x2000), 0x80);
+        assert_eq!(flags(&cpu), EXTEND | ZERO);
+    }
+
+    #[test]
+    fn moves() {
+        //move.b d0,d1 ; move.w (a0)+,-(a1) ; movea.w d2,a2 ; moveq #-1,d3
+        let mut cpu = cpu(&[0x1200, 0x3318, 0x3442, 0x76ff]);
+        cpu.d[0] = 0x80;
+        cpu.d[1] = 0x1234_5678;
+        cpu.a[0] = 0x2000;
+        cpu.a[1] = 0x2012;
+        cpu.memory.mem_write(0x2000, 0xbeef, 2);
+        cpu.d[2] = 0x8000;
+        cpu.sr = EXTEND | CARRY | OVERFLOW;
+        cpu.run();
+        assert_eq!(cpu.d[1], 0x1234_5680);
+        assert_eq!(flags(&cpu), EXTEND | NEGATIVE);
+        cpu.run();
+        assert_eq!(cpu.memory.read_w(0x2010), 0xbeef);
+        assert_eq!((cpu.a[0], cpu.a[1]), (0x2002, 0x2010));
+        //MOVEA sign extends and leaves the flags alone
+        cpu.sr = ZERO;
+        cpu.run();
+        assert_eq!(cpu.a[2], 0xffff_8000);
+        assert_eq!(flags(&cpu), ZERO);
+        cpu.run();
+        assert_eq!(cpu.d[3], 0xffff_ffff);
+        assert_eq!(flags(&cpu), NEGATIVE);
+    }
+
+    #[test]
+    fn sr_ccr_and_usp_moves() {
+        //move sr,d0 ; move #$1f,ccr ; move a0,usp ; move usp,a1 ; move #$0700,sr
+        let mut cpu = cpu(&[0x40c0, 0x44fc, 0x001f, 0x4e60, 0x4e69, 0x46fc, 0x0700]);
+        cpu.sr = SUPERVISOR | 0x0300;
+        cpu.d[0] = 0xffff_ffff;
+        cpu.a[0] = 0x4000;
+        cpu.run();
+        assert_eq!(cpu.d[0], 0xffff_2300);
+        cpu.run();
+        assert_eq!(cpu.sr, SUPERVISOR | 0x031f);
+        cpu.run();
+        assert_eq!(cpu.usp, 0x4000);
+        cpu.run();
+        assert_eq!(cpu.a[1], 0x4000);
+        //dropping to user mode switches to the USP
+        cpu.run();
+        assert_eq!(cpu.sr, 0x0700);
+        assert_eq!(cpu.a[7], 0x4000);
+    }
+
+    #[test]
+    fn sr_and_usp_moves_in_user_mode() {
+        //reading the SR is allowed, but writing it or touching the USP isn't
+        let mut cpu = cpu(&[0x40c0]);
+        cpu.run();
+        assert_eq!(cpu.d[0], 0);
+        assert_eq!(cpu.pc, 0x1002);
+        assert_exception(&[0x46fc, 0x2700], PRIVILEGE_VIOLATION);
+        assert_exception(&[0x4e60], PRIVILEGE_VIOLATION);
+    }
+
+    #[test]
+    fn movep() {
+        //movep.l d0,1(a0) ; movep.w 1(a0),d1
+        let mut cpu = cpu(&[0x01c8, 0x0001, 0x0308, 0x0001]);
+        cpu.d[0] = 0x1122_3344;
+        cpu.d[1] = 0xaaaa_aaaa;
+        cpu.a[0] = 0x2000;
+        cpu.run();
+        //every other byte, high byte first, and odd addresses are fine
+        assert_eq!(cpu.memory.read_b(0x2001), 0x11);
+        assert_eq!(cpu.memory.read_b(0x2003), 0x22);
+        assert_eq!(cpu.memory.read_b(0x2005), 0x33);
+        assert_eq!(cpu.memory.read_b(0x2007), 0x44);
+        assert_eq!(cpu.memory.read_b(0x2002), 0);
+        cpu.run();
+        assert_eq!(cpu.d[1], 0xaaaa_1122);
+    }
+
+    #[test]
+    fn returns() {
+        //rts ; rtr ; rte
+        let mut cpu = cpu(&[0x4e75, 0x4e77, 0x4e73]);
+        cpu.a[7] = 0x7ffc;
+        cpu.memory.mem_write(0x7ffc, 0x1002, 4);
+        cpu.run();
+        assert_eq!((cpu.pc, cpu.a[7]), (0x1002, 0x8000));
+        //RTR only restores the CCR
+        cpu.a[7] = 0x7ffa;
+        cpu.memory.mem_write(0x7ffa, 0xff1f, 2);
+        cpu.memory.mem_write(0x7ffc, 0x1004, 4);
+        cpu.run();
+        assert_eq!(cpu.sr, 0x1f);
+        assert_eq!((cpu.pc, cpu.a[7]), (0x1004, 0x8000));
+        //RTE restores the whole SR
+        cpu.sr = SUPERVISOR;
+        cpu.a[7] = 0x7ffa;
+        cpu.memory.mem_write(0x7ffa, 0x2704, 2);
+        cpu.memory.mem_write(0x7ffc, 0x3000, 4);
+        cpu.run();
+        assert_eq!(cpu.sr, 0x2704);
+        assert_eq!((cpu.pc, cpu.a[7]), (0x3000, 0x8000));
+        assert_exception(&[0x4e73], PRIVILEGE_VIOLATION);
+    }
+
+    #[test]
+    fn traps() {
+        //trap #3 ; trapv ; chk.w d1,d0
+        let mut cpu = cpu(&[0x4e43, 0x4e76, 0x4e76, 0x4181]);
+        cpu.memory.mem_write((TRAP_BASE as usize + 3) * 4, 0x3000, 4);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.sr, SUPERVISOR);
+        //the frame goes on the supervisor stack: the return address, then
+        //the SR from before
+        assert_eq!(cpu.a[7], 0x9000 - 6);
+        assert_eq!(cpu.usp, 0x8000);
+        assert_eq!(cpu.memory.read_w(0x9000 - 6), 0);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1002);
+        cpu.memory.mem_write(TRAPV as usize * 4, 0x3100, 4);
+        cpu.pc = 0x1002;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1004); //V clear
+        cpu.sr |= OVERFLOW;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3100);
+        cpu.memory.mem_write(CHK as usize * 4, 0x3200, 4);
+        cpu.pc = 0x1006;
+        cpu.d[0] = 10;
+        cpu.d[1] = 5;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3200);
+        assert_eq!(flags(&cpu) & NEGATIVE, 0);
+    }
+
+    #[test]
+    fn illegal_and_unimplemented_opcodes() {
+        //illegal ; line A ; line F; these stack the opcode's own address
+        assert_exception(&[0x4afc], ILLEGAL_INSTRUCTION);
+        assert_exception(&[0xa123], LINE_A);
+        assert_exception(&[0xf123], LINE_F);
+    }
+
+    #[test]
+    fn unusable_addressing_mode_is_illegal() {
+        //jmp (a0)+ ; tst.w with mode 7 register 5
+        for &op in &[0x4ed8, 0x4a7d] {
+            let mut cpu = cpu(&[op]);
+            cpu.memory.mem_write(ILLEGAL_INSTRUCTION as usize * 4, 0x3000, 4);
+            cpu.a[0] = 0x2000;
+            cpu.run();
+            assert_eq!(cpu.pc, 0x3000, "{:04x}", op);
+            assert_eq!(cpu.a[0], 0x2000);
+        }
+    }
+
+    #[test]
+    fn stack_pointer_swaps() {
+        //move #0,sr ; trap #0 ; nop ; handler: rte
+        let mut cpu = cpu(&[0x46fc, 0x0000, 0x4e40, 0x4e71, 0x4e73]);
+        cpu.memory.mem_write(TRAP_BASE as usize * 4, 0x1008, 4);
+        cpu.sr = SUPERVISOR;
+        cpu.a[7] = 0x9000;
+        cpu.usp = 0x8000;
+        cpu.ssp = 0;
+        //down to user mode, where A7 is the USP and the SSP is put away
+        cpu.run();
+        assert_eq!((cpu.a[7], cpu.usp, cpu.ssp), (0x8000, 0x8000, 0x9000));
+        //the trap goes back to the supervisor stack
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1008);
+        assert_eq!(cpu.sr, SUPERVISOR);
+        assert_eq!((cpu.a[7], cpu.usp), (0x9000 - 6, 0x8000));
+        //and RTE pops the frame off it and returns to user mode
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1006);
+        assert_eq!(cpu.sr, 0);
+        assert_eq!((cpu.a[7], cpu.usp, cpu.ssp), (0x8000, 0x8000, 0x9000));
+    }
+
+    #[test]
+    fn interrupt_masking_and_vectors() {
+        let mut cpu = cpu(&[0x4e71; 16]);
+        cpu.memory.mem_write((AUTOVECTOR_BASE as usize + 4) * 4, 0x1008, 4);
+        cpu.memory.mem_write(64 * 4, 0x1010, 4);
+        cpu.sr = SUPERVISOR | 0x0300;
+        cpu.a[7] = 0x9000;
+        //at the mask is masked
+        cpu.set_ipl(3, None);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1002);
+        //above it is taken through the autovector, and the mask goes up to it
+        cpu.set_ipl(4, None);
+        assert_eq!(cpu.run(), Status::Running);
+        assert_eq!(cpu.pc, 0x1008);
+        assert_eq!(cpu.sr, SUPERVISOR | 0x0400);
+        assert_eq!(cpu.memory.read_w(0x9000 - 6), SUPERVISOR | 0x0300);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1002);
+        //so it isn't taken again while it's still asserted
+        cpu.run();
+        assert_eq!(cpu.pc, 0x100a);
+        //a device can supply its own vector
+        cpu.set_ipl(5, Some(64));
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1010);
+        assert_eq!(cpu.sr, SUPERVISOR | 0x0500);
+    }
+
+    #[test]
+    fn level_7_is_edge_triggered() {
+        let mut cpu = cpu(&[0x4e71; 16]);
+        cpu.memory.mem_write((AUTOVECTOR_BASE as usize + 7) * 4, 0x1010, 4);
+        cpu.sr = SUPERVISOR | 0x0700;
+        cpu.a[7] = 0x9000;
+        //it can't be masked
+        cpu.set_ipl(7, None);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1010);
+        //but it's only taken once for as long as it's held
+        cpu.run();
+        cpu.set_ipl(7, None);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1014);
+        //dropping it and asserting it again is a new edge
+        cpu.set_ipl(0, None);
+        cpu.run();
+        cpu.set_ipl(7, None);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1010);
+    }
+
+    #[test]
+    fn stop_waits_for_an_interrupt() {
+        //stop #$2300 ; nop
+        let mut cpu = cpu(&[0x4e72, 0x2300, 0x4e71]);
+        cpu.memory.mem_write((AUTOVECTOR_BASE as usize + 4) * 4, 0x1004, 4);
+        cpu.sr = SUPERVISOR | 0x0700;
+        cpu.a[7] = 0x9000;
+        assert_eq!(cpu.run(), Status::Stopped);
+        assert_eq!(cpu.sr, SUPERVISOR | 0x0300);
+        assert_eq!(cpu.run(), Status::Stopped);
+        //an interrupt at the new mask doesn't wake it
+        cpu.set_ipl(3, None);
+        assert_eq!(cpu.run(), Status::Stopped);
+        assert_eq!(cpu.pc, 0x1004);
+        //one above it does, and returns to after the immediate
+        cpu.set_ipl(4, None);
+        assert_eq!(cpu.run(), Status::Running);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1004);
+        assert_eq!(cpu.run(), Status::Running);
+        assert_eq!(cpu.pc, 0x1006);
+    }
+
+    #[test]
+    fn stop_is_privileged() {
+        assert_exception(&[0x4e72, 0x2700], PRIVILEGE_VIOLATION);
+    }
+
+    //A CPU with handlers for tracing, TRAP #0, illegal instructions and
+    //privilege violations at 0x3000, 0x3100, 0x3200 and 0x3300.
+    fn tracing_cpu(code: &[u16], sr: u16) -> M68k {
+        let mut cpu = cpu(code);
+        cpu.memory.mem_write(TRACE_EXCEPTION as usize * 4, 0x3000, 4);
+        cpu.memory.mem_write(TRAP_BASE as usize * 4, 0x3100, 4);
+        cpu.memory.mem_write(ILLEGAL_INSTRUCTION as usize * 4, 0x3200, 4);
+        cpu.memory.mem_write(PRIVILEGE_VIOLATION as usize * 4, 0x3300, 4);
+        cpu.sr = sr;
+        cpu
+    }
+
+    #[test]
+    fn trace_starts_and_stops_with_the_instruction_after() {
+        //ori.w #$8000,sr ; nop
+        let mut cpu = tracing_cpu(&[0x007c, 0x8000, 0x4e71], SUPERVISOR);
+        cpu.a[7] = 0x9000;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1004);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.sr, SUPERVISOR); //T is off in the handler
+        assert_eq!(cpu.memory.read_w(0x9000 - 6), TRACE | SUPERVISOR);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1006);
+        //andi.w #$7fff,sr
+        let mut cpu = tracing_cpu(&[0x027c, 0x7fff], TRACE | SUPERVISOR);
+        cpu.a[7] = 0x9000;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.memory.read_w(0x9000 - 6), SUPERVISOR);
+    }
+
+    #[test]
+    fn trace_after_a_trap() {
+        //trap #0 is traced on the way into its handler
+        let mut cpu = tracing_cpu(&[0x4e40], TRACE);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        //the trace frame is below the trap's, returning to the trap handler
+        assert_eq!(cpu.memory.read_l(0x9000 - 10), 0x3100);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1002);
+    }
+
+    #[test]
+    fn exceptions_that_abort_are_not_traced() {
+        let mut cpu = tracing_cpu(&[0x4afc], TRACE);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3200);
+        assert_eq!(cpu.a[7], 0x9000 - 6);
+        let mut cpu = tracing_cpu(&[0x4e72, 0x2700], TRACE);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3300);
+        assert_eq!(cpu.a[7], 0x9000 - 6);
+    }
+
+    #[test]
+    fn trace_ends_a_stop() {
+        //stop #$a700 leaves T set, so it's traced straight away
+        let mut cpu = tracing_cpu(&[0x4e72, 0xa700], TRACE | SUPERVISOR);
+        cpu.a[7] = 0x9000;
+        assert_eq!(cpu.run(), Status::Running);
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1004);
+    }
+
+    #[test]
+    fn hard_reset() {
+        let mut cpu = cpu(&[0x4e71]);
+        cpu.memory.mem_write(0, 0x0001_0000, 4);
+        cpu.memory.mem_write(4, 0x1000, 4);
+        cpu.memory.mem_write((AUTOVECTOR_BASE as usize + 7) * 4, 0x3000, 4);
+        cpu.sr = TRACE | 0x1f;
+        cpu.set_ipl(7, None);
+        cpu.hard_reset();
+        assert_eq!(cpu.sr, 0x2700);
+        assert_eq!((cpu.a[7], cpu.pc), (0x0001_0000, 0x1000));
+        //the level 7 edge from before the reset is gone
+        cpu.raw = true;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1002);
+    }
+
+    #[test]
+    fn reset_instruction() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        let pulses = Rc::new(Cell::new(0));
+        let counter = pulses.clone();
+        //reset ; reset
+        let mut cpu = cpu(&[0x4e70, 0x4e70]);
+        cpu.memory.mem_write(PRIVILEGE_VIOLATION as usize * 4, 0x3000, 4);
+        cpu.on_reset(Box::new(move || counter.set(counter.get() + 1)));
+        cpu.sr = SUPERVISOR;
+        cpu.a[7] = 0x9000;
+        cpu.run();
+        assert_eq!(pulses.get(), 1);
+        assert_eq!(cpu.sr, SUPERVISOR); //the CPU itself isn't reset
+        //only the supervisor can reset the devices
+        cpu.sr = 0;
+        cpu.a[7] = 0x8000;
+        cpu.run();
+        assert_eq!(pulses.get(), 1);
+        assert_eq!(cpu.pc, 0x3000);
+    }
+
+    #[test]
+    fn address_error() {
+        //moveq #5,d0 ; movea.l #1,a0 ; move.w (a0),d0
+        let mut cpu = cpu(&[0x7005, 0x207c, 0x0000, 0x0001, 0x3010]);
+        cpu.memory.mem_write(ADDRESS_ERROR as usize * 4, 0x3000, 4);
+        cpu.run();
+        cpu.run();
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.d[0], 5);
+        //status word, access address, opcode, SR, PC
+        assert_eq!(cpu.a[7], 0x9000 - 14);
+        assert_eq!(cpu.memory.read_w(0x9000 - 14), 0b10001);
+        assert_eq!(cpu.memory.read_l(0x9000 - 12), 1);
+        assert_eq!(cpu.memory.read_w(0x9000 - 8), 0x3010);
+        assert_eq!(cpu.memory.read_w(0x9000 - 6), 0);
+        assert_eq!(cpu.memory.read_l(0x9000 - 4), 0x1008);
+    }
+
+    #[test]
+    fn address_error_on_a_write() {
+        //move.l d0,(a0) in supervisor mode leaves memory alone
+        let mut cpu = cpu(&[0x2080]);
+        cpu.memory.mem_write(ADDRESS_ERROR as usize * 4, 0x3000, 4);
+        cpu.sr = SUPERVISOR;
+        cpu.a[7] = 0x9000;
+        cpu.a[0] = 0x2001;
+        cpu.d[0] = 0xdead_beef;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.memory.read_w(0x9000 - 14), 0b00101); //write, supervisor data
+        assert_eq!(cpu.memory.read_l(0x2000), 0);
+    }
+
+    #[test]
+    fn address_error_on_a_fetch() {
+        //jmp (a0) to an odd address faults on the next fetch
+        let mut cpu = cpu(&[0x4ed0]);
+        cpu.memory.mem_write(ADDRESS_ERROR as usize * 4, 0x3000, 4);
+        cpu.a[0] = 0x1001;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x1001);
+        cpu.run();
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.memory.read_w(0x9000 - 14), 0b10010); //read, user program
+        assert_eq!(cpu.memory.read_l(0x9000 - 12), 0x1001);
+    }
+
+    #[test]
+    fn double_fault_halts() {
+        //move.w (a0),d0 with an odd supervisor stack to take it on
+        let mut cpu = cpu(&[0x3010]);
+        cpu.a[0] = 0x2001;
+        cpu.ssp = 0x9001;
+        assert_eq!(cpu.run(), Status::Halted);
+        assert_eq!(cpu.run(), Status::Halted);
+        //until a hard reset
+        cpu.memory.mem_write(0, 0x9000, 4);
+        cpu.memory.mem_write(4, 0x1000, 4);
+        cpu.hard_reset();
+        cpu.raw = true;
+        assert_eq!(cpu.run(), Status::Running);
     }
 }
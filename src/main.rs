@@ -1,22 +1,43 @@
+extern crate rust_m68k;
+
+use rust_m68k::m68k;
 use std::env;
 use std::fs::File;
-use std::io;
-mod m68k;
 
 fn main() {
     let mut params = env::args();
     params.next();
-    let f = params.next();
-    match f{
+    let mut f = params.next();
+    //--reset boots the file from the vector table at its start, like a ROM,
+    //instead of running it as code from address 0
+    let boot = f.as_deref() == Some("--reset");
+    if boot {
+        f = params.next();
+    }
+    let f = match f {
         None => {
             println!("Please provide the name of a binary file");
             return;
         }
-        _ => {}
+        Some(f) => f,
+    };
+    let file: File = File::open(f).unwrap();
+    let mut cpu = m68k::M68k::init();
+    cpu.load(file).unwrap();
+    if boot {
+        cpu.hard_reset();
+    }
+    loop {
+        m68k::debug_print(&cpu);
+        match cpu.run() {
+            m68k::Status::Running => {}
+            m68k::Status::Stopped => {
+                //there's nothing attached that could interrupt it
+                println!("stopped");
+                break;
+            }
+            m68k::Status::Halted => break,
+        }
     }
-    let file: File = File::open(&(f).unwrap()).unwrap();
-    let mut myCPU = m68k::M68k::init();
-    myCPU.load(file);
-    while (myCPU.run() == true) {}
-    m68k::debug_print(&myCPU);
+    m68k::debug_print(&cpu);
 }